@@ -0,0 +1,50 @@
+use std::fmt;
+use app_core::{api::ErrorResponse, validate::FieldError};
+use hyper::StatusCode;
+
+/**
+ * Why a call to the API failed
+ */
+#[derive(Debug, Clone)]
+pub enum ClientError {
+    /** The API refused or failed the request, and said why */
+    Api { status: StatusCode, error: ErrorResponse },
+    /** The request couldn't be sent, or the response couldn't be read */
+    Request(String),
+    /** The response isn't what the API sends, e.g. a gateway's error page */
+    InvalidResponse { status: StatusCode, message: String },
+}
+
+impl ClientError {
+    /**
+     * The API's machine readable reason, e.g. "not_found" or "conflict", if the API gave one
+     */
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Api { error, .. } => Some(&error.code),
+            _ => None,
+        }
+    }
+
+    /**
+     * The fields that were invalid, if that's why the request failed
+     */
+    pub fn fields(&self) -> &[FieldError] {
+        match self {
+            ClientError::Api { error, .. } => &error.fields,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Api { status, error } => write!(f, "{} ({}): {}", error.code, status.as_u16(), error.error),
+            ClientError::Request(message) => write!(f, "Couldn't call the API: {}", message),
+            ClientError::InvalidResponse { status, message } => write!(f, "Unexpected response ({}): {}", status.as_u16(), message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,275 @@
+use app_core::{Member, Topic, api::*};
+use hyper::{Body, Method, Request, client::HttpConnector, header};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub mod error;
+
+pub use error::ClientError;
+
+/**
+ * Calls the sin(ln) API with the `app_core::api` types, e.g. from scripts and other services.
+ * Paths without a method of their own can be called with `call`.
+ */
+#[derive(Clone)]
+pub struct Client {
+    base_url: String,
+    token: Option<String>,
+    http: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Client {
+    /**
+     * Calls the API at the URL, e.g. "https://api.sinln.mdsimmo.com" or "http://localhost:9000" for the dev server
+     */
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Client {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token: None,
+            http: hyper::Client::builder().build(connector),
+        }
+    }
+
+    /**
+     * Signs each request in with the bearer token
+     */
+    pub fn with_token(self, token: impl Into<String>) -> Self {
+        Client { token: Some(token.into()), ..self }
+    }
+
+    /**
+     * Sends the input to the path, e.g. "/members-trash", and reads what it answers with
+     */
+    pub async fn call<T: Serialize, R: DeserializeOwned>(&self, path: &str, input: &T) -> Result<R, ClientError> {
+        let body = serde_json::to_string(input).map_err(|err| ClientError::Request(err.to_string()))?;
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", self.base_url, path))
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(body)).map_err(|err| ClientError::Request(err.to_string()))?;
+
+        let response = self.http.request(request).await.map_err(|err| ClientError::Request(err.to_string()))?;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(|err| ClientError::Request(err.to_string()))?;
+        if status.is_success() {
+            return serde_json::from_slice(&bytes).map_err(|err| ClientError::InvalidResponse { status, message: err.to_string() });
+        }
+        match serde_json::from_slice(&bytes) {
+            Ok(error) => Err(ClientError::Api { status, error }),
+            Err(_) => Err(ClientError::InvalidResponse { status, message: String::from_utf8_lossy(&bytes).into_owned() }),
+        }
+    }
+
+    /**
+     * Lists every page, starting from the request's cursor. The response has no `next_cursor`.
+     */
+    async fn list_all<T: DeserializeOwned>(&self, path: &str, mut request: ListRequest) -> Result<ListResponse<T>, ClientError> {
+        let mut all = ListResponse { items: vec![], next_cursor: None, invalid_rows: vec![] };
+        loop {
+            let page: ListResponse<T> = self.call(path, &request).await?;
+            all.items.extend(page.items);
+            all.invalid_rows.extend(page.invalid_rows);
+            match page.next_cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    /**
+     * Lists one page of members. Members that aren't admins only see themselves.
+     * Requests that choose `fields` should use `call`, since the members won't have every field.
+     */
+    pub async fn list_members(&self, request: &ListRequest) -> Result<ListResponse<Member>, ClientError> {
+        self.call("/members-list", request).await
+    }
+
+    pub async fn list_all_members(&self, request: ListRequest) -> Result<ListResponse<Member>, ClientError> {
+        self.list_all("/members-list", request).await
+    }
+
+    pub async fn get_members(&self, request: &GetRequest) -> Result<GetResponse<Member>, ClientError> {
+        self.call("/members-get", request).await
+    }
+
+    /**
+     * Creates members without an id, and replaces those with one
+     */
+    pub async fn update_members(&self, request: &UpdateRequest<Member>) -> Result<UpdateResponse<Member>, ClientError> {
+        self.call("/members-update", request).await
+    }
+
+    pub async fn patch_members(&self, request: &PatchRequest) -> Result<UpdateResponse<Member>, ClientError> {
+        self.call("/members-patch", request).await
+    }
+
+    /**
+     * Moves members to the trash
+     */
+    pub async fn delete_members(&self, request: &DeleteRequest) -> Result<DeleteResponse<Member>, ClientError> {
+        self.call("/members-delete", request).await
+    }
+
+    /**
+     * Lists one page of topics. Requests that choose `fields` should use `call`.
+     */
+    pub async fn list_topics(&self, request: &ListRequest) -> Result<ListResponse<Topic>, ClientError> {
+        self.call("/topics-list", request).await
+    }
+
+    pub async fn list_all_topics(&self, request: ListRequest) -> Result<ListResponse<Topic>, ClientError> {
+        self.list_all("/topics-list", request).await
+    }
+
+    pub async fn get_topics(&self, request: &GetRequest) -> Result<GetResponse<Topic>, ClientError> {
+        self.call("/topics-get", request).await
+    }
+
+    /**
+     * Creates topics without an id, and replaces those with one
+     */
+    pub async fn update_topics(&self, request: &UpdateRequest<Topic>) -> Result<UpdateResponse<Topic>, ClientError> {
+        self.call("/topics-update", request).await
+    }
+
+    pub async fn patch_topics(&self, request: &PatchRequest) -> Result<UpdateResponse<Topic>, ClientError> {
+        self.call("/topics-patch", request).await
+    }
+
+    /**
+     * Moves topics to the trash
+     */
+    pub async fn delete_topics(&self, request: &DeleteRequest) -> Result<DeleteResponse<Topic>, ClientError> {
+        self.call("/topics-delete", request).await
+    }
+
+    /**
+     * Takes a topic out of a member's subscriptions, with the token from the link in an email. Needs no bearer token.
+     */
+    pub async fn unsubscribe(&self, request: &UnsubscribeRequest) -> Result<UnsubscribeResponse, ClientError> {
+        self.call("/members-unsubscribe", request).await
+    }
+
+    /**
+     * Approves an email sent to a topic the caller moderates, so it is sent on to the topic's members
+     */
+    pub async fn confirm_email(&self, request: &ConfirmEmailRequest) -> Result<ConfirmEmailResponse, ClientError> {
+        self.call("/email-confirm", request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use app_core::{Member, Role, Topic, api::*};
+    use app_server_core::{auth::Authenticator, config::AppConfig, handlers::api, router::Router, store::{Expect, MemoryStore, Row, Store}};
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use hyper::{Server, StatusCode, service::{make_service_fn, service_fn}};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode, get_current_timestamp};
+    use serde_json::json;
+
+    use super::{Client, ClientError};
+
+    fn token() -> String {
+        let claims = json!({
+            "sub": "user-1",
+            "email": "admin@example.com",
+            "email_verified": true,
+            "iss": "https://auth.example.com/",
+            "aud": "sinln",
+            "exp": get_current_timestamp() + 600,
+        });
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"test-secret-with-at-least-32-bytes")).unwrap()
+    }
+
+    fn member(name: &str, email: &str) -> Member {
+        Member {
+            id: None,
+            name: name.to_string(),
+            email: email.to_string(),
+            address: None,
+            mobile: None,
+            subscriptions: vec![],
+            role: Role::Member,
+            version: None,
+        }
+    }
+
+    /**
+     * Serves the API on a free port, as the dev server does, and gives its URL
+     */
+    async fn serve() -> String {
+        let config: &'static AppConfig = Box::leak(Box::new(AppConfig::for_tests()));
+        let store: &'static MemoryStore = Box::leak(Box::new(MemoryStore::new()));
+        let mut admin = Row::new();
+        admin.insert("id".to_string(), AttributeValue::S("1".to_string()));
+        admin.insert("name".to_string(), AttributeValue::S("Admin".to_string()));
+        admin.insert("email".to_string(), AttributeValue::S("admin@example.com".to_string()));
+        admin.insert("email_key".to_string(), AttributeValue::S("admin@example.com".to_string()));
+        admin.insert("role".to_string(), AttributeValue::S("admin".to_string()));
+        store.put("members", admin, Expect::Anything).await.unwrap();
+        let auth: &'static Authenticator = Box::leak(Box::new(Authenticator::from_config(config, store).unwrap()));
+        let router: &'static Router = Box::leak(Box::new(api(config, store, auth)));
+
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<hyper::Body>| async move {
+                let (parts, body) = request.into_parts();
+                let body = String::from_utf8(hyper::body::to_bytes(body).await?.to_vec())?;
+                let response = router.handle(lambda_http::Request::from_parts(parts, lambda_http::Body::Text(body))).await?;
+                Ok::<_, lambda_http::Error>(response.map(hyper::Body::from))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let url = serve().await;
+        let client = Client::new(format!("{}/", url)).with_token(token());
+
+        let request = UpdateRequest { values: vec![member("Ann", "ann@example.com"), member("Bob", "bob@example.com")], all_or_nothing: true };
+        let response = client.update_members(&request).await.unwrap();
+        assert!(response.updates.iter().all(|update| update.error.is_none()));
+
+        let page = client.list_members(&ListRequest { limit: Some(2), ..Default::default() }).await.unwrap();
+        assert_eq!(page.items.len(), 2);
+        assert!(page.next_cursor.is_some());
+        let all = client.list_all_members(ListRequest { limit: Some(2), ..Default::default() }).await.unwrap();
+        assert_eq!(all.items.len(), 3);
+        assert!(all.next_cursor.is_none());
+
+        let topic = Topic { id: None, name: "News".to_string(), endpoint: "news@example.com".to_string(), default: false, moderators: vec![], version: None };
+        let response = client.update_topics(&UpdateRequest { values: vec![topic], all_or_nothing: true }).await.unwrap();
+        let id = response.updates[0].current.id.clone().unwrap();
+        let response = client.delete_topics(&DeleteRequest { ids: vec![id], all_or_nothing: true }).await.unwrap();
+        assert_eq!(response.deletes[0].removed.as_ref().unwrap().name, "News");
+        assert!(client.list_topics(&ListRequest::default()).await.unwrap().items.is_empty());
+
+        let request = UpdateRequest { values: vec![member("Cat", "not an email")], all_or_nothing: true };
+        let err = client.update_members(&request).await.unwrap_err();
+        assert_eq!(err.code(), Some("validation"));
+        assert_eq!(err.fields()[0].field, "values[0].email");
+
+        let err = Client::new(&url).list_members(&ListRequest::default()).await.unwrap_err();
+        assert!(matches!(err, ClientError::Api { status: StatusCode::UNAUTHORIZED, .. }));
+
+        // Served by its own lambda, so the dev server doesn't have it
+        let request = ConfirmEmailRequest { topic_id: "1".to_string(), email_id: "1".to_string(), token: "1".to_string() };
+        assert_eq!(client.confirm_email(&request).await.unwrap_err().code(), Some("not_found"));
+
+        let err = Client::new("http://127.0.0.1:1").list_topics(&ListRequest::default()).await.unwrap_err();
+        assert!(matches!(err, ClientError::Request(_)));
+    }
+}
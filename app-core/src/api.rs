@@ -1,46 +1,347 @@
+use std::collections::HashMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::Topic;
+use crate::{Broadcast, Topic, validate::FieldError};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/**
+ * Why a single item of a bulk request failed
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ItemError {
+    /** A machine readable reason, e.g. "conflict", "not_found", "aborted" */
+    pub code: String,
+    pub message: String,
+}
+impl ItemError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        ItemError { code: code.to_string(), message: message.into() }
+    }
+}
+
+/**
+ * The body of every error response
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ErrorResponse {
+    /** A machine readable reason, e.g. "validation", "not_found", "forbidden" */
+    pub code: String,
+    pub error: String,
+    /** The fields that were invalid, if that's why the request failed */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/**
+ * Reads items by their ids
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct GetRequest {
+    pub ids: Vec<String>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}GetResponse")]
+pub struct GetResponse<T> {
+    /** The items that were found, in the order they were asked for */
+    pub items: Vec<T>,
+    /** The ids that have no item, including those in the trash */
+    pub missing: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct DeleteRequest {
     pub ids: Vec<String>,
+    /** Only delete the items if all of them can be deleted */
+    #[serde(default)]
+    pub all_or_nothing: bool,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}DeleteStatus")]
+pub struct DeleteStatus<T> {
+    pub id: String,
+    /** The item that was moved to the trash, or None if there was no item */
+    pub removed: Option<T>,
+    /** Set if the item wasn't deleted */
+    pub error: Option<ItemError>,
 }
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}DeleteResponse")]
 pub struct DeleteResponse<T> {
-    pub removed: Vec<Option<T>>,
+    pub deletes: Vec<DeleteStatus<T>>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/**
+ * Lists items that have been deleted, but not yet purged
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ListTrashRequest {
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}TrashedItem")]
+pub struct TrashedItem<T> {
+    pub item: T,
+    /** When the item was deleted, in seconds since the unix epoch */
+    pub deleted_at: u64,
+    /** When the item can be purged, in seconds since the unix epoch */
+    pub purge_after: u64,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}ListTrashResponse")]
+pub struct ListTrashResponse<T> {
+    pub items: Vec<TrashedItem<T>>,
+    pub next_cursor: Option<String>,
+}
+
+/**
+ * Takes deleted items out of the trash
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct RestoreRequest {
+    pub ids: Vec<String>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}RestoreStatus")]
+pub struct RestoreStatus<T> {
+    pub id: String,
+    /** The item as it is after being restored */
+    pub restored: Option<T>,
+    /** Set if the item wasn't restored */
+    pub error: Option<ItemError>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}RestoreResponse")]
+pub struct RestoreResponse<T> {
+    pub restores: Vec<RestoreStatus<T>>,
+}
+
+/**
+ * Permanently removes items from the trash.
+ * If no ids are given, every item that has been in the trash longer than the retention period is purged.
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct PurgeRequest {
+    #[serde(default)]
+    pub ids: Vec<String>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct PurgeResponse {
+    /** The ids of the items that were purged */
+    pub purged: Vec<String>,
+}
+
+/**
+ * Lists the recorded changes to items, newest first
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ListAuditRequest {
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+    /** Only list changes to the item with this id */
+    pub entity_id: Option<String>,
+    /** Only list changes made at or after this time, in seconds since the unix epoch */
+    pub since: Option<u64>,
+    /** Only list changes made before this time, in seconds since the unix epoch */
+    pub until: Option<u64>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+}
+/**
+ * A single change to an item
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}AuditEntry")]
+pub struct AuditEntry<T> {
+    pub id: String,
+    /** When the change was made, in seconds since the unix epoch */
+    pub timestamp: u64,
+    /** Who made the change */
+    pub actor: String,
+    /** The type of item that was changed */
+    pub entity: String,
+    /** The id of the item that was changed */
+    pub entity_id: String,
+    pub action: AuditAction,
+    /** The item before the change, or None if it didn't exist (or isn't known) */
+    pub before: Option<T>,
+    /** The item after the change, or None if it no longer exists */
+    pub after: Option<T>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}ListAuditResponse")]
+pub struct ListAuditResponse<T> {
+    pub entries: Vec<AuditEntry<T>>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}UpdateRequest")]
 pub struct UpdateRequest<T> {
     pub values: Vec<T>,
+    /** Only write the items if all of them can be written */
+    #[serde(default)]
+    pub all_or_nothing: bool,
 }
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}UpdateStatus")]
 pub struct UpdateStatus<T> {
     pub replaced: Option<T>,
+    /** The item as it was written, or the unwritten item if there is an error */
     pub current: T,
+    /** Set if the item wasn't written because it was changed by someone else */
+    pub conflict: Option<VersionConflict<T>>,
+    /** Set if the item wasn't written */
+    pub error: Option<ItemError>,
 }
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}VersionConflict")]
+pub struct VersionConflict<T> {
+    /** The version that the update expected to replace */
+    pub expected_version: u64,
+    /** The item as it is currently stored, or None if it no longer exists */
+    pub server_copy: Option<T>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}UpdateResponse")]
 pub struct UpdateResponse<T> {
     pub updates: Vec<UpdateStatus<T>>,
 }
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct ListRequest {
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct PatchRequest {
+    pub patches: Vec<Patch>,
+}
+/**
+ * Changes some fields of a stored item, leaving the other fields as they are.
+ * Each field may only be changed by one of set/unset/add/remove.
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct Patch {
+    pub id: String,
+    /** Only apply the patch if the stored item is at this version */
+    pub expected_version: Option<u64>,
+    /** Fields to give a new value */
+    #[serde(default)]
+    pub set: HashMap<String, PatchValue>,
+    /** Optional fields to clear */
+    #[serde(default)]
+    pub unset: Vec<String>,
+    /** Values to add to list fields (e.g. subscriptions) */
+    #[serde(default)]
+    pub add: HashMap<String, Vec<String>>,
+    /** Values to remove from list fields (e.g. subscriptions) */
+    #[serde(default)]
+    pub remove: HashMap<String, Vec<String>>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum PatchValue {
+    Bool(bool),
+    Integer(i64),
+    Decimal(f64),
+    Text(String),
+    List(Vec<String>),
+}
 
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    /** The field is exactly the value */
+    Equals,
+    /** The field is a string starting with the value */
+    Prefix,
+    /** The field is a string containing the value, or a list containing the value */
+    Contains,
 }
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct Filter {
+    pub field: String,
+    pub op: FilterOp,
+    pub value: String,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct Sort {
+    pub field: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ListRequest {
+    /** The maximum number of items to return in one page */
+    pub limit: Option<u32>,
+    /** The `next_cursor` from a previous page, to continue listing from */
+    pub cursor: Option<String>,
+    /** Only items matching every filter are listed */
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    /** The field to order items by. Items are in id order if not given. */
+    pub sort: Option<Sort>,
+    /** The fields to include in each item (the id is always included). All fields if not given. */
+    pub fields: Option<Vec<String>>,
+    /** Fail the whole request if any row can't be read, instead of reporting it in `invalid_rows` */
+    #[serde(default)]
+    pub strict: bool,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(rename = "{T}ListResponse")]
 pub struct ListResponse<T> {
     pub items: Vec<T>,
+    /** Pass as the `cursor` of the next request to get the next page. None when there are no more pages. */
+    pub next_cursor: Option<String>,
+    /** Rows in this page that couldn't be read as an item */
+    #[serde(default)]
+    pub invalid_rows: Vec<InvalidRow>,
+}
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRow {
+    /** The id of the row, if it has one */
+    pub id: Option<String>,
+    /** Why the row couldn't be read */
+    pub error: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct ConfirmEmailRequest {
     pub topic_id: String,
     pub email_id: String,
+    /** The signed token from the confirm link in the email */
+    pub token: String,
 }
 
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct ConfirmEmailResponse {
     pub topic: Option<Topic>,
-}
\ No newline at end of file
+    /** Where sending the email is up to. Missing if there is no such topic. */
+    #[serde(default)]
+    pub broadcast: Option<Broadcast>,
+    /** Whether this request queued the email. False if it had already been confirmed, so it isn't sent twice. */
+    #[serde(default)]
+    pub queued: bool,
+}
+
+/**
+ * Stops a member getting emails sent to a topic, from the link at the bottom of each email
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct UnsubscribeRequest {
+    pub member_id: String,
+    pub topic_id: String,
+    /** The signed token from the unsubscribe link in the email */
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct UnsubscribeResponse {
+    /** The name of the topic, to show the member */
+    pub topic: String,
+    /** False if the member already wasn't subscribed */
+    pub unsubscribed: bool,
+}
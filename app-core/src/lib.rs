@@ -1,3 +1,4 @@
 mod types;
 pub mod api;
+pub mod validate;
 pub use self::types::*;
\ No newline at end of file
@@ -1,20 +1,181 @@
 extern crate serde;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct Member {
     pub id: Option<String>,
     pub name: String,
     pub email: String,
     pub address: Option<String>,
-    pub mobile: Option<u64>,
+    pub mobile: Option<PhoneNumber>,
     pub subscriptions: Vec<String>,
+    /** What the member is allowed to do. Only admins can change it. Required, so an update that leaves it out can't demote anyone. */
+    pub role: Role,
+    /** The version of the stored row. Send it back when updating to only write if no one else has changed it since. */
+    #[serde(default)]
+    pub version: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct Topic {
     pub id: Option<String>,
     pub name: String,
     pub endpoint: String,
     pub default: bool,
-}
\ No newline at end of file
+    /** The ids of the members who approve emails sent to the topic. Required, so an update that leaves it out can't remove them. */
+    pub moderators: Vec<String>,
+    /** The version of the stored row. Send it back when updating to only write if no one else has changed it since. */
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+impl Topic {
+    /**
+     * Whether one of a member's `subscriptions` is to this topic
+     */
+    pub fn has_subscription(&self, subscription: &str) -> bool {
+        self.endpoint.contains(subscription)
+    }
+}
+
+/**
+ * What a member is allowed to do
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /** Manages topics and every member */
+    Admin,
+    /** Approves the emails sent to the topics they moderate */
+    Moderator,
+    /** Reads and edits only their own record */
+    #[default]
+    Member,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Member => "member",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "admin" => Some(Role::Admin),
+            "moderator" => Some(Role::Moderator),
+            "member" => Some(Role::Member),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * An email sent to a topic, from when it arrives until it has been queued for every member.
+ * There is one per topic and email, so confirming an email twice doesn't send it twice.
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Broadcast {
+    pub id: Option<String>,
+    pub topic_id: String,
+    pub email_id: String,
+    pub status: BroadcastStatus,
+    /** Who confirmed the email should be sent */
+    pub confirmed_by: Option<String>,
+    /** How many members the email was queued for, once it has been sent */
+    pub recipients: Option<u64>,
+    /** When queueing last made progress, in seconds since the unix epoch */
+    pub progress_at: Option<u64>,
+    /** The ids of the members the email has been queued for so far. Kept out of responses. */
+    #[serde(skip)]
+    pub queued_members: Vec<String>,
+    /** The version of the stored row */
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+/**
+ * Where a broadcast is up to. It moves forward in this order, except that a failed broadcast goes back to
+ * sending when it is confirmed again.
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastStatus {
+    /** Waiting for a moderator to confirm it */
+    Pending,
+    /** Confirmed, but not yet being queued */
+    Confirmed,
+    /** Being queued for the topic's members */
+    Sending,
+    /** Queued for every member */
+    Sent,
+    /** Queueing stopped part way. Confirming it again queues it for the members it missed. */
+    Failed,
+}
+
+impl BroadcastStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BroadcastStatus::Pending => "pending",
+            BroadcastStatus::Confirmed => "confirmed",
+            BroadcastStatus::Sending => "sending",
+            BroadcastStatus::Sent => "sent",
+            BroadcastStatus::Failed => "failed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BroadcastStatus> {
+        match name {
+            "pending" => Some(BroadcastStatus::Pending),
+            "confirmed" => Some(BroadcastStatus::Confirmed),
+            "sending" => Some(BroadcastStatus::Sending),
+            "sent" => Some(BroadcastStatus::Sent),
+            "failed" => Some(BroadcastStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * A phone number in E.164 format, e.g. "+61412345678".
+ * Spaces, dashes, dots and brackets are dropped when it is created; call `is_valid` to check the rest.
+ */
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PhoneNumber(String);
+
+impl PhoneNumber {
+    pub fn new(text: &str) -> Self {
+        PhoneNumber(text.chars().filter(|c| !" -.()".contains(*c)).collect())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /**
+     * Checks the number is a '+' followed by a country code and up to 15 digits in total
+     */
+    pub fn is_valid(&self) -> bool {
+        match self.0.strip_prefix('+') {
+            Some(digits) => (1..=15).contains(&digits.len())
+                && digits.chars().all(|c| c.is_ascii_digit())
+                && !digits.starts_with('0'),
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PhoneNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PhoneNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|text| PhoneNumber::new(&text))
+    }
+}
@@ -0,0 +1,188 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{Member, Topic};
+
+/** Characters other than letters and digits allowed in an unquoted part of an email address (RFC 5322 atext) */
+const ATEXT_SYMBOLS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+/** The longest local part (before the '@') of an email address that mail servers must accept */
+const MAX_LOCAL_PART: usize = 64;
+
+/** The longest email address that fits in an SMTP path */
+const MAX_EMAIL: usize = 254;
+
+/**
+ * A problem with one field of a request
+ */
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /** The path to the field, e.g. "values[1].email" */
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError { field: field.into(), message: message.into() }
+    }
+
+    /**
+     * Puts the path of the item holding this field in front of the field, e.g. "email" becomes "values[1].email"
+     */
+    pub fn within(self, path: &str) -> Self {
+        FieldError { field: format!("{}.{}", path, self.field), message: self.message }
+    }
+}
+
+/**
+ * What the rules depend on that differs between deployments
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationContext {
+    /** Topics may only send from addresses at this domain (or its subdomains). Any domain is allowed when None. */
+    pub sending_domain: Option<String>,
+}
+
+/**
+ * Checks an item is fit to be stored
+ */
+pub trait Validate {
+    /** Lists every problem with the item. It is valid if the list is empty. */
+    fn validate(&self, context: &ValidationContext) -> Vec<FieldError>;
+}
+
+impl Validate for Member {
+    fn validate(&self, _context: &ValidationContext) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if self.name.trim().is_empty() {
+            errors.push(FieldError::new("name", "Name can't be empty"));
+        }
+        if !is_email(&self.email) {
+            errors.push(FieldError::new("email", format!("Not a valid email address: {}", self.email)));
+        }
+        if let Some(mobile) = self.mobile.as_ref().filter(|mobile| !mobile.is_valid()) {
+            errors.push(FieldError::new("mobile", format!("Not an international phone number (e.g. +61412345678): {}", mobile)));
+        }
+        errors
+    }
+}
+
+impl Validate for Topic {
+    fn validate(&self, context: &ValidationContext) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if self.name.trim().is_empty() {
+            errors.push(FieldError::new("name", "Name can't be empty"));
+        }
+        if !is_email(&self.endpoint) {
+            errors.push(FieldError::new("endpoint", format!("Not a valid email address: {}", self.endpoint)));
+        } else if let Some(domain) = &context.sending_domain {
+            if !in_domain(&self.endpoint, domain) {
+                errors.push(FieldError::new("endpoint", format!("Emails can only be sent from addresses at {}", domain)));
+            }
+        }
+        errors
+    }
+}
+
+/**
+ * Checks the text is an email address as written in RFC 5322 (`local-part@domain`), without comments or folding whitespace
+ */
+pub fn is_email(text: &str) -> bool {
+    // A quoted local part can hold an '@', but the domain can't
+    let (local, domain) = match text.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    text.len() <= MAX_EMAIL
+        && local.len() <= MAX_LOCAL_PART
+        && (is_dot_atom(local) || is_quoted_string(local))
+        && (is_dot_atom(domain) || is_domain_literal(domain))
+}
+
+/**
+ * Checks the email address is at the domain or one of its subdomains
+ */
+pub fn in_domain(email: &str, domain: &str) -> bool {
+    let email_domain = match email.rsplit_once('@') {
+        Some((_, email_domain)) => email_domain.to_lowercase(),
+        None => return false,
+    };
+    let domain = domain.to_lowercase();
+    email_domain == domain || email_domain.ends_with(&format!(".{}", domain))
+}
+
+/** Words of atext separated by single dots, e.g. "first.last" */
+fn is_dot_atom(text: &str) -> bool {
+    text.split('.').all(|atom| {
+        !atom.is_empty() && atom.chars().all(|c| c.is_ascii_alphanumeric() || ATEXT_SYMBOLS.contains(c))
+    })
+}
+
+/** Printable text in double quotes, where '"' and '\' are escaped with a '\', e.g. "\"john smith\"" */
+fn is_quoted_string(text: &str) -> bool {
+    let inner = match text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return false,
+    };
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let valid = match c {
+            '\\' => chars.next().is_some_and(|escaped| escaped == ' ' || escaped.is_ascii_graphic()),
+            '"' => false,
+            c => c == ' ' || c.is_ascii_graphic(),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+/** A domain given directly in square brackets, e.g. "[192.168.0.1]" */
+fn is_domain_literal(text: &str) -> bool {
+    match text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+        Some(inner) => inner.chars().all(|c| c.is_ascii_graphic() && !"[]\\".contains(c)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Topic, PhoneNumber};
+    use super::{Validate, ValidationContext, is_email};
+
+    #[test]
+    fn test_is_email() {
+        for valid in ["alice@example.com", "first.last+tag@mail.example.org", "\"john smith\"@example.com", "\"a@b\"@example.com", "user@[192.168.0.1]", "o'brien@localhost"] {
+            assert!(is_email(valid), "{} should be valid", valid);
+        }
+        for invalid in ["", "alice", "alice@", "@example.com", "alice..b@example.com", ".alice@example.com", "alice@example..com", "john smith@example.com", "alice@exa mple.com", "\"unclosed@example.com"] {
+            assert!(!is_email(invalid), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_phone_number() {
+        assert_eq!(PhoneNumber::new("+61 412-345 (678)").as_str(), "+61412345678");
+        assert!(PhoneNumber::new("+61 412 345 678").is_valid());
+        assert!(!PhoneNumber::new("0412345678").is_valid());
+        assert!(!PhoneNumber::new("+0412345678").is_valid());
+        assert!(!PhoneNumber::new("+1234567890123456").is_valid());
+        assert!(!PhoneNumber::new("+61abc").is_valid());
+    }
+
+    #[test]
+    fn test_topic_domain() {
+        let topic = |endpoint: &str| Topic { id: None, name: "News".to_string(), endpoint: endpoint.to_string(), default: false, moderators: vec![], version: None };
+        let context = ValidationContext { sending_domain: Some("example.com".to_string()) };
+        assert!(topic("news@example.com").validate(&context).is_empty());
+        assert!(topic("news@lists.Example.com").validate(&context).is_empty());
+        assert!(topic("news@example.com").validate(&ValidationContext::default()).is_empty());
+
+        let errors = topic("news@badexample.com").validate(&context);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "endpoint");
+        assert_eq!(topic("not an email").validate(&context)[0].field, "endpoint");
+    }
+}
@@ -0,0 +1,216 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use app_core::api::{AuditAction, AuditEntry, ListAuditRequest, ListAuditResponse, Filter, FilterOp, Sort};
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::Error;
+use rand::Rng;
+
+use crate::{ApiError, crud::{sorted_page, write_batches}, query::sort_rows, serialize::ServerSerialize, store::{Store, Scan, Row, BatchWrite}};
+
+/** How many entries this process has recorded */
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * Records who changed what. Entries are only ever added, never changed or removed.
+ */
+#[derive(Clone, Copy)]
+pub struct Audit<'a> {
+    store: &'a dyn Store,
+    table: &'a str,
+    actor: &'a str,
+}
+
+/**
+ * A change to a single item
+ */
+pub struct Change<'a> {
+    pub action: AuditAction,
+    pub id: &'a str,
+    pub before: Option<Row>,
+    pub after: Option<Row>,
+}
+
+impl<'a> Audit<'a> {
+    /**
+     * Records changes made by the actor into the table
+     */
+    pub fn new(store: &'a dyn Store, table: &'a str, actor: &'a str) -> Self {
+        Audit { store, table, actor }
+    }
+
+    pub fn actor(&self) -> &str {
+        self.actor
+    }
+
+    /**
+     * Writes an entry for each change to items in the entity's table.
+     * The changes have already been made, so failing to record them is logged rather than returned.
+     */
+    pub async fn record(&self, entity: &str, changes: Vec<Change<'_>>) {
+        if changes.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let writes: Vec<BatchWrite> = changes.into_iter().map(|change| {
+            // Ids start with the time so they sort oldest to newest, then a count for changes in the same millisecond
+            let count = COUNT.fetch_add(1, Ordering::Relaxed) % 1_000_000;
+            let id = format!("{:013}-{:06}-{:010}", timestamp.as_millis(), count, rand::thread_rng().gen::<u32>());
+            let mut row = Row::new();
+            row.insert("id".to_string(), AttributeValue::S(id));
+            row.insert("timestamp".to_string(), AttributeValue::N(timestamp.as_secs().to_string()));
+            row.insert("actor".to_string(), AttributeValue::S(self.actor.to_string()));
+            row.insert("entity".to_string(), AttributeValue::S(entity.to_string()));
+            row.insert("entity_id".to_string(), AttributeValue::S(change.id.to_string()));
+            row.insert("action".to_string(), AttributeValue::S(action_name(change.action).to_string()));
+            if let Some(before) = change.before {
+                row.insert("before".to_string(), AttributeValue::M(before));
+            }
+            if let Some(after) = change.after {
+                row.insert("after".to_string(), AttributeValue::M(after));
+            }
+            BatchWrite::Put(row)
+        }).collect();
+
+        let errors = write_batches(self.store, self.table, writes).await;
+        for error in errors.into_iter().flatten() {
+            log::error!("Failed to record change to {} by {}: {}", entity, self.actor, error.message);
+        }
+    }
+}
+
+fn action_name(action: AuditAction) -> &'static str {
+    match action {
+        AuditAction::Create => "create",
+        AuditAction::Update => "update",
+        AuditAction::Delete => "delete",
+        AuditAction::Restore => "restore",
+        AuditAction::Purge => "purge",
+    }
+}
+
+fn read_action(name: &str) -> Result<AuditAction, ApiError> {
+    match name {
+        "create" => Ok(AuditAction::Create),
+        "update" => Ok(AuditAction::Update),
+        "delete" => Ok(AuditAction::Delete),
+        "restore" => Ok(AuditAction::Restore),
+        "purge" => Ok(AuditAction::Purge),
+        _ => Err(ApiError::Internal(format!("Unknown audit action: {}", name))),
+    }
+}
+
+/**
+ * Lists the recorded changes to items of the entity, newest first
+ */
+pub async fn list_audit<T: ServerSerialize>(store: &dyn Store, input: ListAuditRequest, table: &str, entity: &str) -> Result<ListAuditResponse<T>, Error> {
+    let mut filters = vec![Filter { field: "entity".to_string(), op: FilterOp::Equals, value: entity.to_string() }];
+    if let Some(entity_id) = input.entity_id {
+        filters.push(Filter { field: "entity_id".to_string(), op: FilterOp::Equals, value: entity_id });
+    }
+    let scan = Scan {
+        filters,
+        ..Default::default()
+    };
+
+    // Entries are read out of order, so sort them all before paging
+    let mut rows = store.scan_all(table, &scan).await?;
+    rows.retain(|row| {
+        let timestamp = row.get("timestamp").and_then(|time| time.as_n().ok()).and_then(|time| time.parse::<u64>().ok());
+        timestamp.is_some_and(|timestamp| {
+            input.since.is_none_or(|since| timestamp >= since) && input.until.is_none_or(|until| timestamp < until)
+        })
+    });
+    sort_rows(&mut rows, &Sort { field: "id".to_string(), descending: true });
+    let page = sorted_page(rows, input.limit, input.cursor.as_deref())?;
+
+    let mut entries = vec![];
+    for row in &page.rows {
+        match read_entry(row) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => log::warn!("Skipping invalid audit entry {:?}: {}", row.get("id"), err),
+        }
+    }
+    Ok(ListAuditResponse {
+        entries,
+        next_cursor: page.next_cursor,
+    })
+}
+
+fn read_entry<T: ServerSerialize>(row: &Row) -> Result<AuditEntry<T>, ApiError> {
+    let text = |key: &str| match row.get(key).map(|value| value.as_s()) {
+        Some(Ok(value)) => Ok(value.clone()),
+        _ => Err(ApiError::Internal(format!("No value for: {}", key))),
+    };
+    let item = |key: &str| match row.get(key).map(|value| value.as_m()) {
+        Some(Ok(item)) => T::from_row(item).map(Some),
+        _ => Ok(None),
+    };
+    Ok(AuditEntry {
+        id: text("id")?,
+        timestamp: row.get("timestamp").and_then(|time| time.as_n().ok()).and_then(|time| time.parse().ok())
+            .ok_or_else(|| ApiError::Internal("No value for: timestamp".to_string()))?,
+        actor: text("actor")?,
+        entity: text("entity")?,
+        entity_id: text("entity_id")?,
+        action: read_action(&text("action")?)?,
+        before: item("before")?,
+        after: item("after")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use app_core::{Member, Role, api::{AuditAction, ListAuditRequest, UpdateRequest, DeleteRequest}, validate::ValidationContext};
+
+    use crate::crud::{IdStrategy, RowAccess, update_items, delete_items};
+    use crate::store::MemoryStore;
+    use super::{Audit, list_audit};
+
+    fn member(name: &str) -> Member {
+        Member {
+            id: None,
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+            address: None,
+            mobile: None,
+            subscriptions: vec![],
+            role: Role::Member,
+            version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let store = MemoryStore::new();
+        let audit_store = MemoryStore::new();
+        let audit = Audit::new(&audit_store, "audit", "admin@example.com");
+
+        let created = update_items(&store, UpdateRequest { values: vec![member("alice"), member("bob")], all_or_nothing: false }, "members", IdStrategy::Timestamped, &ValidationContext::default(), &RowAccess::All, &audit).await.unwrap();
+        let mut alice = created.updates[0].current.clone();
+        alice.email = "alice@example.org".to_string();
+        update_items(&store, UpdateRequest { values: vec![alice.clone()], all_or_nothing: false }, "members", IdStrategy::Timestamped, &ValidationContext::default(), &RowAccess::All, &audit).await.unwrap();
+        let alice_id = alice.id.clone().unwrap();
+        delete_items::<Member>(&store, DeleteRequest { ids: vec![alice_id.clone()], all_or_nothing: false }, "members", &RowAccess::All, &audit).await.unwrap();
+
+        let request = ListAuditRequest { entity_id: Some(alice_id.clone()), ..Default::default() };
+        let listed = list_audit::<Member>(&audit_store, request, "audit", "members").await.unwrap();
+        let actions: Vec<_> = listed.entries.iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, vec![AuditAction::Delete, AuditAction::Update, AuditAction::Create]);
+        let changed = &listed.entries[1];
+        assert_eq!(changed.actor, "admin@example.com");
+        assert_eq!(changed.entity, "members");
+        assert_eq!(changed.before.as_ref().unwrap().email, "alice@example.com");
+        assert_eq!(changed.after.as_ref().unwrap().email, "alice@example.org");
+        assert!(listed.entries[0].after.is_none());
+
+        // Filter by time and page through the entries
+        let all = list_audit::<Member>(&audit_store, ListAuditRequest { limit: Some(3), ..Default::default() }, "audit", "members").await.unwrap();
+        assert_eq!(all.entries.len(), 3);
+        let rest = ListAuditRequest { limit: Some(3), cursor: all.next_cursor, ..Default::default() };
+        assert_eq!(list_audit::<Member>(&audit_store, rest, "audit", "members").await.unwrap().entries.len(), 1);
+        let future = ListAuditRequest { since: Some(changed.timestamp + 1), ..Default::default() };
+        assert!(list_audit::<Member>(&audit_store, future, "audit", "members").await.unwrap().entries.is_empty());
+        let other = list_audit::<Member>(&audit_store, ListAuditRequest::default(), "audit", "topics").await.unwrap();
+        assert!(other.entries.is_empty());
+    }
+}
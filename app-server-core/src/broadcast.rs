@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+use app_core::{Broadcast, BroadcastStatus, Member};
+use lambda_http::Error;
+
+use crate::{ApiError, serialize::ServerSerialize, store::{Store, Expect, WriteResult}};
+
+/** How long a `Sending` broadcast can go without progress before another confirm takes it over, in seconds */
+pub const SENDING_TIMEOUT: u64 = 5 * 60;
+
+/** How many members are queued between each save of the progress */
+const PROGRESS_INTERVAL: usize = 25;
+
+/**
+ * What confirming a broadcast did
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Confirmation {
+    /** The broadcast is now `Sending` for this caller alone, who must queue the email with `send` */
+    Claimed(Broadcast),
+    /** The broadcast had already been confirmed. Holds where it is up to. */
+    AlreadyConfirmed(Broadcast),
+}
+
+/**
+ * The id of the broadcast of an email to a topic
+ */
+pub fn broadcast_id(topic_id: &str, email_id: &str) -> String {
+    format!("{}/{}", topic_id, email_id)
+}
+
+/**
+ * Records that an email is waiting for a moderator to confirm it.
+ * Recording it again (e.g. when the incoming email is retried) keeps the broadcast where it is up to.
+ */
+pub async fn add_pending(store: &dyn Store, table: &str, topic_id: &str, email_id: &str) -> Result<Broadcast, Error> {
+    let broadcast = Broadcast {
+        version: Some(1),
+        ..new_broadcast(topic_id, email_id)
+    };
+    match store.put(table, broadcast.into_row(), Expect::Version(None)).await? {
+        WriteResult::Written(_) => Ok(broadcast),
+        WriteResult::Conflict(Some(row)) => Ok(Broadcast::from_row(&row)?),
+        WriteResult::Conflict(None) => Err(ApiError::Internal(format!("Cannot record broadcast {}", broadcast_id(topic_id, email_id))).into()),
+    }
+}
+
+/**
+ * Moves the broadcast on through `Confirmed` to `Sending`, unless it has been confirmed already.
+ * Each step only writes if no one has moved the broadcast on since it was read, so when the confirm link is
+ * followed twice at once only one of them claims it. Emails that arrived before broadcasts were recorded start here.
+ * A `Failed` broadcast, or one that has been `Sending` for `SENDING_TIMEOUT` without progress, is claimed again.
+ */
+pub async fn confirm(store: &dyn Store, table: &str, topic_id: &str, email_id: &str, actor: &str) -> Result<Confirmation, Error> {
+    let id = broadcast_id(topic_id, email_id);
+    let mut broadcast = match store.get(table, &id).await? {
+        Some(row) => Broadcast::from_row(&row)?,
+        None => new_broadcast(topic_id, email_id),
+    };
+    loop {
+        let mut next = Broadcast {
+            version: Some(broadcast.version.unwrap_or(0) + 1),
+            ..broadcast.clone()
+        };
+        match broadcast.status {
+            BroadcastStatus::Pending => {
+                next.status = BroadcastStatus::Confirmed;
+                next.confirmed_by = Some(actor.to_string());
+            },
+            BroadcastStatus::Sending if !stalled(&broadcast) => return Ok(Confirmation::AlreadyConfirmed(broadcast)),
+            BroadcastStatus::Sent => return Ok(Confirmation::AlreadyConfirmed(broadcast)),
+            BroadcastStatus::Confirmed | BroadcastStatus::Sending | BroadcastStatus::Failed => {
+                next.status = BroadcastStatus::Sending;
+                next.progress_at = Some(now());
+            },
+        }
+        broadcast = match store.put(table, next.into_row(), Expect::Version(broadcast.version)).await? {
+            WriteResult::Written(_) if next.status == BroadcastStatus::Sending => return Ok(Confirmation::Claimed(next)),
+            WriteResult::Written(_) => next,
+            WriteResult::Conflict(Some(row)) => Broadcast::from_row(&row)?,
+            WriteResult::Conflict(None) => return Err(ApiError::NotFound(format!("Broadcast {} was removed while confirming it", id)).into()),
+        };
+    }
+}
+
+/**
+ * Queues the email with `queue` for each of the members it hasn't been queued for yet, then marks the claimed
+ * broadcast `Sent`. Progress is saved every `PROGRESS_INTERVAL` members, so a send that fails (it is marked `Failed`)
+ * or stalls can be resumed by confirming again, and at most the members queued since the last save get it twice.
+ */
+pub async fn send<'a, F, Fut>(store: &dyn Store, table: &str, mut broadcast: Broadcast, members: impl IntoIterator<Item = &'a Member>, queue: F) -> Result<Broadcast, Error>
+    where F: Fn(&'a Member) -> Fut,
+    Fut: Future<Output = Result<(), Error>>
+{
+    let mut queued: HashSet<String> = broadcast.queued_members.iter().cloned().collect();
+    let mut unsaved = 0;
+    for member in members {
+        let id = member.id.clone().unwrap_or_default();
+        if queued.contains(&id) {
+            continue;
+        }
+        if let Err(err) = queue(member).await {
+            broadcast.status = BroadcastStatus::Failed;
+            if let Err(save_err) = save(store, table, &mut broadcast).await {
+                log::error!("Cannot mark broadcast {} failed: {}", broadcast.id.as_deref().unwrap_or_default(), save_err);
+            }
+            return Err(err);
+        }
+        queued.insert(id.clone());
+        broadcast.queued_members.push(id);
+        unsaved += 1;
+        if unsaved == PROGRESS_INTERVAL {
+            save(store, table, &mut broadcast).await?;
+            unsaved = 0;
+        }
+    }
+    broadcast.status = BroadcastStatus::Sent;
+    broadcast.recipients = Some(broadcast.queued_members.len() as u64);
+    save(store, table, &mut broadcast).await?;
+    Ok(broadcast)
+}
+
+/**
+ * Writes the broadcast's progress, unless someone else has changed it since, e.g. by taking over a stalled send
+ */
+async fn save(store: &dyn Store, table: &str, broadcast: &mut Broadcast) -> Result<(), Error> {
+    let expected = broadcast.version;
+    broadcast.version = Some(expected.unwrap_or(0) + 1);
+    broadcast.progress_at = Some(now());
+    match store.put(table, broadcast.into_row(), Expect::Version(expected)).await? {
+        WriteResult::Written(_) => Ok(()),
+        WriteResult::Conflict(_) => Err(ApiError::Conflict(format!("Broadcast {} changed while it was being sent", broadcast.id.as_deref().unwrap_or_default())).into()),
+    }
+}
+
+/** Whether a `Sending` broadcast has gone too long without progress, e.g. because its sender was stopped */
+fn stalled(broadcast: &Broadcast) -> bool {
+    broadcast.progress_at.unwrap_or(0) + SENDING_TIMEOUT <= now()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn new_broadcast(topic_id: &str, email_id: &str) -> Broadcast {
+    Broadcast {
+        id: Some(broadcast_id(topic_id, email_id)),
+        topic_id: topic_id.to_string(),
+        email_id: email_id.to_string(),
+        status: BroadcastStatus::Pending,
+        confirmed_by: None,
+        recipients: None,
+        progress_at: None,
+        queued_members: vec![],
+        version: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use app_core::{BroadcastStatus, Member, Role};
+    use futures::join;
+
+    use crate::{ApiError, serialize::ServerSerialize, store::{Expect, MemoryStore, Store}};
+    use super::{Confirmation, add_pending, confirm, send};
+
+    fn member(id: &str) -> Member {
+        Member {
+            id: Some(id.to_string()),
+            name: id.to_string(),
+            email: format!("{}@example.com", id),
+            address: None,
+            mobile: None,
+            subscriptions: vec![],
+            role: Role::Member,
+            version: None,
+        }
+    }
+
+    fn claimed(confirmation: Confirmation) -> app_core::Broadcast {
+        match confirmation {
+            Confirmation::Claimed(broadcast) => broadcast,
+            other => panic!("Expected to claim the broadcast: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let store = MemoryStore::new();
+        let pending = add_pending(&store, "broadcasts", "t1", "email-1").await.unwrap();
+        assert_eq!(pending.status, BroadcastStatus::Pending);
+        assert_eq!(pending.id.as_deref(), Some("t1/email-1"));
+        // The incoming email being retried changes nothing
+        assert_eq!(add_pending(&store, "broadcasts", "t1", "email-1").await.unwrap(), pending);
+
+        let broadcast = claimed(confirm(&store, "broadcasts", "t1", "email-1", "ann@example.com").await.unwrap());
+        assert_eq!(broadcast.status, BroadcastStatus::Sending);
+        assert_eq!(broadcast.confirmed_by.as_deref(), Some("ann@example.com"));
+
+        // Confirming again, even before it has been sent, doesn't claim it again
+        let again = confirm(&store, "broadcasts", "t1", "email-1", "bob@example.com").await.unwrap();
+        assert_eq!(again, Confirmation::AlreadyConfirmed(broadcast.clone()));
+
+        let members = [member("m1"), member("m2"), member("m3")];
+        let sent = send(&store, "broadcasts", broadcast, &members, |_| async { Ok(()) }).await.unwrap();
+        assert_eq!(sent.status, BroadcastStatus::Sent);
+        match confirm(&store, "broadcasts", "t1", "email-1", "ann@example.com").await.unwrap() {
+            Confirmation::AlreadyConfirmed(broadcast) => assert_eq!(broadcast.recipients, Some(3)),
+            other => panic!("Expected the sent broadcast: {:?}", other),
+        }
+        // Pending again can't undo it
+        assert_eq!(add_pending(&store, "broadcasts", "t1", "email-1").await.unwrap().status, BroadcastStatus::Sent);
+
+        // Only one of two confirms at once claims the broadcast, including for emails that were never recorded
+        let (first, second) = join!(
+            confirm(&store, "broadcasts", "t1", "email-2", "ann@example.com"),
+            confirm(&store, "broadcasts", "t1", "email-2", "bob@example.com"),
+        );
+        let claimed = [first.unwrap(), second.unwrap()].iter().filter(|confirmation| matches!(confirmation, Confirmation::Claimed(_))).count();
+        assert_eq!(claimed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_send() {
+        let store = MemoryStore::new();
+        let members = [member("m1"), member("m2"), member("m3")];
+        let queued = Mutex::new(vec![]);
+        let queue = |fail: &'static str| {
+            let queued = &queued;
+            move |member: &Member| {
+                let id = member.id.clone().unwrap();
+                async move {
+                    if id == fail {
+                        return Err(ApiError::Upstream("Queue is down".to_string()).into());
+                    }
+                    queued.lock().unwrap().push(id);
+                    Ok(())
+                }
+            }
+        };
+
+        let broadcast = claimed(confirm(&store, "broadcasts", "t1", "email-1", "ann@example.com").await.unwrap());
+        assert!(send(&store, "broadcasts", broadcast, &members, queue("m2")).await.is_err());
+        let failed = match confirm(&store, "broadcasts", "t1", "email-1", "bob@example.com").await.unwrap() {
+            // Confirming again picks up where it stopped
+            Confirmation::Claimed(broadcast) => broadcast,
+            other => panic!("Expected to claim the failed broadcast: {:?}", other),
+        };
+        assert_eq!(failed.queued_members, vec!["m1"]);
+        assert_eq!(failed.confirmed_by.as_deref(), Some("ann@example.com"));
+
+        let sent = send(&store, "broadcasts", failed, &members, queue("none")).await.unwrap();
+        assert_eq!(sent.status, BroadcastStatus::Sent);
+        assert_eq!(sent.recipients, Some(3));
+        assert_eq!(*queued.lock().unwrap(), vec!["m1", "m2", "m3"]);
+
+        // A send that stopped without saying so is taken over once it has stalled
+        let broadcast = claimed(confirm(&store, "broadcasts", "t1", "email-2", "ann@example.com").await.unwrap());
+        assert!(matches!(confirm(&store, "broadcasts", "t1", "email-2", "ann@example.com").await.unwrap(), Confirmation::AlreadyConfirmed(_)));
+        let mut stalled = broadcast.clone();
+        stalled.progress_at = Some(0);
+        store.put("broadcasts", stalled.into_row(), Expect::Version(broadcast.version)).await.unwrap();
+        let resumed = claimed(confirm(&store, "broadcasts", "t1", "email-2", "ann@example.com").await.unwrap());
+        // The stalled sender can't save over the one that took over
+        assert!(send(&store, "broadcasts", broadcast, &members, queue("none")).await.is_err());
+        assert_eq!(send(&store, "broadcasts", resumed, &members, queue("none")).await.unwrap().recipients, Some(3));
+    }
+}
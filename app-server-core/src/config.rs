@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use app_core::validate::ValidationContext;
+use serde_json::Value;
+
+/**
+ * The environment variable holding the path of an optional JSON config file.
+ * Environment variables override values in the file.
+ */
+pub const CONFIG_FILE_VAR: &str = "SINLN_CONFIG_FILE";
+
+/** Prefix of the environment variable for each setting, e.g. SINLN_MEMBERS_TABLE for `members_table` */
+const ENV_PREFIX: &str = "SINLN_";
+
+/** The shortest secret links may be signed with. Shorter ones could be guessed. */
+const MIN_LINK_KEY_LENGTH: usize = 32;
+
+/**
+ * Names and addresses that differ between deployments. Loaded once when a lambda starts.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppConfig {
+    pub members_table: String,
+    pub topics_table: String,
+    /** The table every change to members and topics is recorded in */
+    pub audit_table: String,
+    /** The table tracking each email moderators are asked to confirm, so it is only sent to the topic once */
+    pub broadcasts_table: String,
+    /** The SQS queue of emails waiting to be sent */
+    pub output_queue_url: String,
+    /** The S3 bucket that incoming emails are stored in */
+    pub input_email_bucket: String,
+    /** Where the web app is hosted, used for links in emails. Has no trailing slash. */
+    pub link_base: String,
+    /** Where the API is hosted, for links that mail apps post to. Has no trailing slash. Defaults to `link_base`. */
+    pub api_base: String,
+    /** The domain verified for sending email. Topics can only send from addresses at it. */
+    pub sending_domain: String,
+    /** Who issues the tokens that callers of the API authenticate with (the `iss` claim) */
+    pub auth_issuer: String,
+    /** Who the tokens must be issued for (the `aud` claim) */
+    pub auth_audience: String,
+    /** A JSON Web Key Set of the keys tokens may be signed with */
+    pub auth_keys: String,
+    /** The secrets links in emails are signed with, newest first. Links are signed with the first and checked against all of them. */
+    pub link_keys: Vec<String>,
+    /** The origins of web apps allowed to call the API from a browser, e.g. "https://sinln.mdsimmo.com". Defaults to the origin of `link_base`. */
+    pub cors_origins: Vec<String>,
+    /** How many days deleted items are kept in the trash before they are purged */
+    pub trash_retention_days: u64,
+}
+
+/**
+ * Why the config couldn't be loaded. Lists every problem, so they can all be fixed at once.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid configuration: {}", self.problems.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl AppConfig {
+    /**
+     * Loads the config from the file named by SINLN_CONFIG_FILE (if set) and SINLN_* environment variables
+     */
+    pub fn load() -> Result<Self, ConfigError> {
+        AppConfig::load_with_defaults(HashMap::new())
+    }
+
+    /**
+     * Like `load`, but settings that the file and environment don't set take the default values
+     */
+    pub fn load_with_defaults(mut values: HashMap<String, String>) -> Result<Self, ConfigError> {
+        if let Ok(path) = std::env::var(CONFIG_FILE_VAR) {
+            values.extend(read_file(&path)?);
+        }
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+                values.insert(name.to_lowercase(), value);
+            }
+        }
+        AppConfig::from_values(&values)
+    }
+
+    /**
+     * Creates the config from setting names (e.g. "members_table") and their values
+     */
+    pub fn from_values(values: &HashMap<String, String>) -> Result<Self, ConfigError> {
+        let mut problems = vec![];
+        let mut text = |name: &str| match values.get(name).map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value.to_string(),
+            _ => {
+                problems.push(format!("{}{} is not set", ENV_PREFIX, name.to_uppercase()));
+                String::new()
+            },
+        };
+
+        let mut config = AppConfig {
+            members_table: text("members_table"),
+            topics_table: text("topics_table"),
+            audit_table: text("audit_table"),
+            broadcasts_table: text("broadcasts_table"),
+            output_queue_url: text("output_queue_url"),
+            input_email_bucket: text("input_email_bucket"),
+            link_base: text("link_base").trim_end_matches('/').to_string(),
+            api_base: String::new(),
+            sending_domain: text("sending_domain").to_lowercase(),
+            auth_issuer: text("auth_issuer"),
+            auth_audience: text("auth_audience"),
+            auth_keys: text("auth_keys"),
+            link_keys: text("link_keys").split(',').map(|key| key.trim().to_string()).filter(|key| !key.is_empty()).collect(),
+            cors_origins: vec![],
+            trash_retention_days: 30,
+        };
+
+        if let Some(days) = values.get("trash_retention_days") {
+            match days.trim().parse() {
+                Ok(days) => config.trash_retention_days = days,
+                Err(_) => problems.push(format!("{}TRASH_RETENTION_DAYS must be a whole number of days, not {:?}", ENV_PREFIX, days)),
+            }
+        }
+        if !config.output_queue_url.is_empty() && !config.output_queue_url.starts_with("https://") {
+            problems.push(format!("{}OUTPUT_QUEUE_URL must be an https:// URL, not {:?}", ENV_PREFIX, config.output_queue_url));
+        }
+        let web_url = config.link_base.starts_with("https://") || config.link_base.starts_with("http://");
+        if !config.link_base.is_empty() && !web_url {
+            problems.push(format!("{}LINK_BASE must be an http(s):// URL, not {:?}", ENV_PREFIX, config.link_base));
+        }
+        config.api_base = match values.get("api_base") {
+            Some(url) => {
+                let url = url.trim().trim_end_matches('/');
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    problems.push(format!("{}API_BASE must be an http(s):// URL, not {:?}", ENV_PREFIX, url));
+                }
+                url.to_string()
+            },
+            None => config.link_base.clone(),
+        };
+        config.cors_origins = match values.get("cors_origins") {
+            Some(origins) => origins.split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            None if web_url => vec![origin_of(&config.link_base).to_string()],
+            None => vec![],
+        };
+        for origin in &config.cors_origins {
+            let web_origin = ["https://", "http://"].iter()
+                .any(|scheme| origin.strip_prefix(scheme).is_some_and(|host| !host.is_empty() && !host.contains('/')));
+            if !web_origin {
+                problems.push(format!("{}CORS_ORIGINS must be a comma separated list of http(s):// origins without paths, not {:?}", ENV_PREFIX, origin));
+            }
+        }
+        if config.link_keys.iter().any(|key| key.len() < MIN_LINK_KEY_LENGTH) {
+            problems.push(format!("{}LINK_KEYS must be comma separated secrets of at least {} characters", ENV_PREFIX, MIN_LINK_KEY_LENGTH));
+        }
+        if config.sending_domain.contains('@') {
+            problems.push(format!("{}SENDING_DOMAIN must be a domain, not an address: {:?}", ENV_PREFIX, config.sending_domain));
+        }
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError { problems }),
+        }
+    }
+
+    /**
+     * The deployment specific rules items are validated against
+     */
+    pub fn validation(&self) -> ValidationContext {
+        ValidationContext {
+            sending_domain: Some(self.sending_domain.clone()),
+        }
+    }
+
+    /**
+     * How long deleted items are kept in the trash before they are purged
+     */
+    pub fn trash_retention(&self) -> Duration {
+        Duration::from_secs(self.trash_retention_days * 24 * 60 * 60)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl AppConfig {
+    /**
+     * The settings tests start from, e.g. tables named "members" and "topics".
+     * Bearer tokens are checked with the HS256 key "test-secret-with-at-least-32-bytes".
+     */
+    pub fn test_values() -> HashMap<String, String> {
+        [
+            ("members_table", "members"),
+            ("topics_table", "topics"),
+            ("audit_table", "audit"),
+            ("broadcasts_table", "broadcasts"),
+            ("output_queue_url", "https://sqs.us-east-1.amazonaws.com/123/output"),
+            ("input_email_bucket", "input"),
+            ("link_base", "https://sinln.mdsimmo.com"),
+            ("sending_domain", "example.com"),
+            ("auth_issuer", "https://auth.example.com/"),
+            ("auth_audience", "sinln"),
+            // base64url of "test-secret-with-at-least-32-bytes"
+            ("auth_keys", r#"{"keys": [{"kty": "oct", "k": "dGVzdC1zZWNyZXQtd2l0aC1hdC1sZWFzdC0zMi1ieXRlcw"}]}"#),
+            ("link_keys", "test-link-secret-with-at-least-32-bytes"),
+        ].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    /**
+     * The config of the `test_values`
+     */
+    pub fn for_tests() -> Self {
+        AppConfig::from_values(&AppConfig::test_values()).unwrap()
+    }
+}
+
+/**
+ * The scheme and host of a URL, e.g. "https://example.com" for "https://example.com/app"
+ */
+fn origin_of(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |index| index + 3);
+    match url[host_start..].find('/') {
+        Some(index) => &url[..host_start + index],
+        None => url,
+    }
+}
+
+/**
+ * Reads settings from a JSON object of setting names to strings or numbers
+ */
+fn read_file(path: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let problem = |problem: String| ConfigError { problems: vec![problem] };
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| problem(format!("Cannot read config file {}: {}", path, err)))?;
+    let object = match serde_json::from_str(&text) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(problem(format!("Config file {} must hold a JSON object", path))),
+        Err(err) => return Err(problem(format!("Config file {} is not valid JSON: {}", path, err))),
+    };
+
+    let mut values = HashMap::new();
+    for (key, value) in object {
+        let value = match value {
+            Value::String(value) => value,
+            Value::Number(value) => value.to_string(),
+            _ => return Err(problem(format!("Config file {} has a value for {} that isn't text or a number", path, key))),
+        };
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::AppConfig;
+
+    fn values() -> HashMap<String, String> {
+        let mut values = AppConfig::test_values();
+        values.insert("members_table".to_string(), "staging-members".to_string());
+        values.insert("link_base".to_string(), "https://staging.example.com/".to_string());
+        values.insert("sending_domain".to_string(), "Example.com".to_string());
+        values.insert("link_keys".to_string(), "new-link-secret-with-at-least-32-bytes, old-link-secret-with-at-least-32-bytes".to_string());
+        values
+    }
+
+    #[test]
+    fn test_from_values() {
+        let config = AppConfig::from_values(&values()).unwrap();
+        assert_eq!(config.members_table, "staging-members");
+        assert_eq!(config.link_base, "https://staging.example.com");
+        assert_eq!(config.trash_retention_days, 30);
+        assert_eq!(config.validation().sending_domain.as_deref(), Some("example.com"));
+        assert_eq!(config.cors_origins, vec!["https://staging.example.com"]);
+        assert_eq!(config.api_base, "https://staging.example.com");
+        assert_eq!(config.link_keys, vec!["new-link-secret-with-at-least-32-bytes", "old-link-secret-with-at-least-32-bytes"]);
+
+        let mut values = values();
+        values.insert("trash_retention_days".to_string(), "7".to_string());
+        assert_eq!(AppConfig::from_values(&values).unwrap().trash_retention().as_secs(), 7 * 24 * 60 * 60);
+
+        values.insert("cors_origins".to_string(), "https://staging.example.com/, http://localhost:3000".to_string());
+        assert_eq!(AppConfig::from_values(&values).unwrap().cors_origins, vec!["https://staging.example.com", "http://localhost:3000"]);
+        values.insert("cors_origins".to_string(), "*".to_string());
+        assert!(AppConfig::from_values(&values).is_err());
+
+        values.insert("cors_origins".to_string(), "https://staging.example.com".to_string());
+        values.insert("api_base".to_string(), "https://api.staging.example.com/".to_string());
+        assert_eq!(AppConfig::from_values(&values).unwrap().api_base, "https://api.staging.example.com");
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let mut values = values();
+        values.remove("topics_table");
+        values.insert("audit_table".to_string(), " ".to_string());
+        values.insert("link_base".to_string(), "staging.example.com".to_string());
+        values.insert("trash_retention_days".to_string(), "a week".to_string());
+        values.insert("link_keys".to_string(), "short".to_string());
+        let err = AppConfig::from_values(&values).unwrap_err();
+        assert_eq!(err.problems.len(), 5);
+        assert_eq!(err.problems[0], "SINLN_TOPICS_TABLE is not set");
+        assert!(err.to_string().contains("SINLN_LINK_BASE must be an http(s):// URL"));
+    }
+}
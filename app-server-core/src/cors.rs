@@ -0,0 +1,116 @@
+use lambda_http::{Error, Request, Response, http::{HeaderValue, Method, StatusCode, header}};
+
+use crate::{config::AppConfig, runtime::StringResponse};
+
+/** The methods the API is called with */
+const ALLOW_METHODS: &str = "POST, OPTIONS";
+
+/** The request headers the web app sends */
+const ALLOW_HEADERS: &str = "Authorization, Content-Type";
+
+/** How many seconds browsers may cache a preflight response for. Chrome won't cache for longer than this anyway. */
+const MAX_AGE: &str = "7200";
+
+/**
+ * Lets web apps at the allowed origins call the API from the browser.
+ * Only the request's own origin is sent back, since a response can only allow one.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Cors<'a> {
+    origins: &'a [String],
+}
+
+impl<'a> Cors<'a> {
+    /**
+     * Allows the origins, e.g. "https://sinln.mdsimmo.com"
+     */
+    pub fn new(origins: &'a [String]) -> Self {
+        Cors { origins }
+    }
+
+    pub fn from_config(config: &'a AppConfig) -> Self {
+        Cors::new(&config.cors_origins)
+    }
+
+    pub fn is_preflight(request: &Request) -> bool {
+        request.method() == Method::OPTIONS
+    }
+
+    /**
+     * The request's `Origin` header, if it is one of the allowed origins
+     */
+    pub fn allowed_origin(&self, request: &Request) -> Option<HeaderValue> {
+        let origin = request.headers().get(header::ORIGIN)?;
+        let text = origin.to_str().ok()?;
+        match self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(text)) {
+            true => Some(origin.clone()),
+            false => None,
+        }
+    }
+
+    /**
+     * Answers the browser's check of whether it may send the request.
+     * Origins that aren't allowed get no CORS headers, so the browser won't send the request.
+     */
+    pub fn preflight(&self, request: &Request) -> Result<StringResponse, Error> {
+        let mut builder = Response::builder().status(StatusCode::NO_CONTENT);
+        if self.allowed_origin(request).is_some() {
+            builder = builder
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, ALLOW_METHODS)
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, ALLOW_HEADERS)
+                .header(header::ACCESS_CONTROL_MAX_AGE, MAX_AGE);
+        }
+        let response = builder.body(String::new()).map_err(Box::new)?;
+        Ok(self.add_headers(self.allowed_origin(request), response))
+    }
+
+    /**
+     * Lets the origin (from `allowed_origin`) read the response
+     */
+    pub fn add_headers(&self, origin: Option<HeaderValue>, mut response: StringResponse) -> StringResponse {
+        let headers = response.headers_mut();
+        // The response differs by origin, so caches must not share it between them
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        if let Some(origin) = origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::{Body, Request, http::{Method, StatusCode, header}};
+
+    use super::Cors;
+
+    fn request(method: Method, origin: &str) -> Request {
+        let mut request = Request::new(Body::Empty);
+        *request.method_mut() = method;
+        request.headers_mut().insert(header::ORIGIN, origin.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_cors() {
+        let origins = vec!["https://sinln.mdsimmo.com".to_string(), "http://localhost:8080".to_string()];
+        let cors = Cors::new(&origins);
+
+        let preflight = request(Method::OPTIONS, "http://localhost:8080");
+        assert!(Cors::is_preflight(&preflight));
+        let response = cors.preflight(&preflight).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:8080");
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "POST, OPTIONS");
+        assert_eq!(response.headers()[header::VARY], "Origin");
+
+        let response = cors.preflight(&request(Method::OPTIONS, "https://evil.example.com")).unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).is_none());
+
+        assert!(!Cors::is_preflight(&request(Method::POST, "https://sinln.mdsimmo.com")));
+        assert_eq!(cors.allowed_origin(&request(Method::POST, "https://SINLN.mdsimmo.com")).unwrap(), "https://SINLN.mdsimmo.com");
+        assert!(cors.allowed_origin(&request(Method::POST, "https://sinln.mdsimmo.com.evil.com")).is_none());
+        assert!(cors.allowed_origin(&Request::new(Body::Empty)).is_none());
+    }
+}
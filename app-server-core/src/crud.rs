@@ -1,119 +1,1687 @@
-use std::collections::HashMap;
-
-use app_core::api::{ListResponse, ListRequest, DeleteResponse, DeleteRequest, UpdateRequest, UpdateResponse, UpdateStatus};
-use aws_sdk_dynamodb::{Client, types::{AttributeValue, ReturnValue}};
-use lambda_http::{Error, aws_lambda_events::chrono};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use app_core::api::{AuditAction, ListResponse, ListRequest, GetRequest, GetResponse, InvalidRow, DeleteResponse, DeleteRequest, DeleteStatus, ItemError, ListTrashRequest, ListTrashResponse, TrashedItem, RestoreRequest, RestoreResponse, RestoreStatus, PurgeRequest, PurgeResponse, UpdateRequest, UpdateResponse, UpdateStatus, VersionConflict, PatchRequest, Patch, PatchValue};
+use app_core::validate::{Validate, ValidationContext, FieldError};
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::stream::{self, StreamExt};
+use lambda_http::Error;
 use rand::Rng;
 
-use crate::{serialize::ServerSerialize, RuntimeError};
-
-
-pub async fn list_items<T: ServerSerialize>(_event: ListRequest, table: &str) -> Result<ListResponse<T>, Error> {
-    // Get all members in dynamodb
-    let config = aws_config::load_from_env().await;
-    let client = Client::new(&config);
-    let table_response = client.scan()
-        .table_name(table)
-        .send().await;
-    let table_response = table_response?;
-
-    // Convert json into members
-    let result = table_response.items()
-        .map(|items| {
-            let items: Vec<T> = items.into_iter().filter_map(|row| {
-                match T::from_row(row) {
-                    Ok(m) => Some(m),
-                    Err(_err) => None,
-                }
-            }).collect();
-            items
-        });
-    
-    // TODO Handle the case of None
-    let items = match result {
-        Some(x) => x,
-        None => return Err(RuntimeError::from_str("Scan resulted in None? Why would that happen?").into()),
-    };
-    
+use crate::{audit::{Audit, Change}, serialize::{ServerSerialize, Projected, write_lookup}, store::{Store, Scan, Page, Row, RowUpdate, Expect, WriteResult, TransactWrite, TransactResult, BatchWrite, MAX_TRANSACTION_WRITES, MAX_BATCH_WRITES, CONDITION_FAILED, VERSION, DELETED_AT, row_version, row_deleted_at}, query::sort_rows, ApiError};
+
+/** How many batches or single writes to send to the store at the same time */
+const MAX_CONCURRENT_WRITES: usize = 8;
+
+/** More lookup values than this are checked with one read of the table, rather than a query each */
+const MAX_LOOKUP_QUERIES: usize = 25;
+
+/** Why a transaction wasn't tried, when one of its items is in the trash */
+const TRASHED: &str = "ItemTrashed";
+
+/** The item was changed by someone else */
+pub const CONFLICT: &str = "conflict";
+/** There is no item with the id */
+pub const NOT_FOUND: &str = "not_found";
+/** The item wasn't changed because the request was all or nothing, and something else failed */
+pub const ABORTED: &str = "aborted";
+/** The item is changed more than once in the same request */
+pub const DUPLICATE: &str = "duplicate";
+/** The store failed to make the change */
+pub const UPSTREAM: &str = "upstream";
+
+/**
+ * How ids are made for new items
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    /** When the item was created then a random number, e.g. "2024-05-01-09:30:00-1234567", so ids sort oldest first */
+    Timestamped,
+    /** A random 128 bit number in hex */
+    Random,
+    /** Callers choose the id (e.g. a name that must be unique). Items without one are invalid. */
+    Given,
+}
+
+impl IdStrategy {
+    /**
+     * Makes an id for a new item, or None if callers must give one
+     */
+    pub fn new_id(&self) -> Option<String> {
+        match self {
+            IdStrategy::Timestamped => {
+                let id_time = chrono::Utc::now();
+                let id_random = rand::thread_rng().gen::<u32>();
+                let mut id_string = id_time.format("%Y-%m-%d-%H:%M:%S-").to_string();
+                id_string.push_str(&id_random.to_string());
+                Some(id_string)
+            },
+            IdStrategy::Random => Some(format!("{:032x}", rand::thread_rng().gen::<u128>())),
+            IdStrategy::Given => None,
+        }
+    }
+}
+
+/**
+ * Which rows a caller may read and change
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowAccess {
+    /** Every row */
+    All,
+    /** Only the stored row with the id, and not its locked fields */
+    Own { id: String, locked_fields: Vec<String> },
+}
+
+impl RowAccess {
+    /**
+     * Checks the caller may change the row with the id. Only `All` can create rows (which have no id yet).
+     */
+    fn check_id(&self, id: Option<&str>) -> Result<(), ApiError> {
+        match self {
+            RowAccess::All => Ok(()),
+            RowAccess::Own { id: own, .. } if id == Some(own) => Ok(()),
+            RowAccess::Own { .. } => Err(ApiError::Forbidden(match id {
+                Some(id) => format!("Only your own record can be changed, not {}", id),
+                None => "Only your own record can be changed, not a new one".to_string(),
+            })),
+        }
+    }
+
+    fn locked_fields(&self) -> &[String] {
+        match self {
+            RowAccess::All => &[],
+            RowAccess::Own { locked_fields, .. } => locked_fields,
+        }
+    }
+
+    /**
+     * Checks the items only change what the caller may change
+     */
+    async fn check_items<T: ServerSerialize>(&self, store: &dyn Store, table_name: &str, items: &[T]) -> Result<(), ApiError> {
+        for item in items {
+            self.check_id(item.id())?;
+        }
+        let own_id = match self {
+            RowAccess::Own { id, .. } if !items.is_empty() => id,
+            _ => return Ok(()),
+        };
+        // Read the stored row as an item, so fields missing from old rows get the same defaults as the new items
+        let stored = match store.get(table_name, own_id).await.map_err(ApiError::from_error)? {
+            Some(row) if !row.contains_key(DELETED_AT) => T::from_row(&row)?.into_row(),
+            _ => return Err(ApiError::Forbidden("Only your own record can be changed, and it doesn't exist".to_string())),
+        };
+        for item in items {
+            let row = item.into_row();
+            if let Some(field) = self.locked_fields().iter().find(|field| row.get(*field) != stored.get(*field)) {
+                return Err(ApiError::Forbidden(format!("Field can only be changed by an admin: {}", field)));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn list_items<T: ServerSerialize>(store: &dyn Store, event: ListRequest, table: &str) -> Result<ListResponse<Projected<T>>, Error> {
+    // Only read the asked for fields, plus whatever is needed to read and sort the items
+    let read_fields = event.fields.as_ref().map(|fields| {
+        let mut read_fields = fields.clone();
+        read_fields.extend(T::REQUIRED_FIELDS.iter().map(|field| field.to_string()));
+        read_fields.extend(event.sort.as_ref().map(|sort| sort.field.clone()));
+        read_fields.sort();
+        read_fields.dedup();
+        read_fields
+    });
+    let scan = Scan {
+        limit: event.limit,
+        cursor: event.cursor,
+        filters: event.filters,
+        fields: read_fields,
+        trashed: false,
+    };
+
+    // Get one page of items in the table
+    let page = match &event.sort {
+        None => store.scan(table, &scan).await?,
+        Some(sort) => {
+            // Sorting needs every matching row, so page through the sorted rows here
+            let mut rows = store.scan_all(table, &scan).await?;
+            sort_rows(&mut rows, sort);
+            sorted_page(rows, scan.limit, scan.cursor.as_deref())?
+        }
+    };
+
+    // Convert rows into items
+    let (items, invalid_rows) = read_rows::<T>(&page.rows);
+    if event.strict {
+        if let Some(invalid) = invalid_rows.first() {
+            let msg = format!("Row {} is invalid: {}", invalid.id.as_deref().unwrap_or("(no id)"), invalid.error);
+            return Err(ApiError::Internal(msg).into());
+        }
+    }
+    let fields = event.fields.map(Arc::new);
+    let items = items.into_iter()
+        .map(|item| Projected::new(item, fields.clone()))
+        .collect();
+
     Ok(ListResponse {
         items,
+        next_cursor: page.next_cursor,
+        invalid_rows,
     })
 }
 
-pub async fn delete_items<T: ServerSerialize>(input: DeleteRequest, table: &str) -> Result<DeleteResponse<T>, Error> {
+/**
+ * Takes the page of sorted rows that comes after the row with the cursor's id
+ */
+pub(crate) fn sorted_page(rows: Vec<Row>, limit: Option<u32>, cursor: Option<&str>) -> Result<Page, ApiError> {
+    let start = match cursor {
+        None => 0,
+        Some(cursor) => match rows.iter().position(|row| row.get("id").and_then(|id| id.as_s().ok()).map(|id| &id[..]) == Some(cursor)) {
+            Some(i) => i + 1,
+            None => return Err(ApiError::Validation("Cursor is not in the list".to_string())),
+        },
+    };
+    let end = match limit {
+        Some(limit) => rows.len().min(start + limit as usize),
+        None => rows.len(),
+    };
+    let next_cursor = if end < rows.len() && end > start {
+        rows[end - 1].get("id").and_then(|id| id.as_s().ok()).cloned()
+    } else {
+        None
+    };
+    Ok(Page {
+        rows: rows.into_iter().skip(start).take(end - start).collect(),
+        next_cursor,
+    })
+}
+
+/**
+ * Converts rows into items, keeping track of the rows that couldn't be converted
+ */
+fn read_rows<T: ServerSerialize>(rows: &[Row]) -> (Vec<T>, Vec<InvalidRow>) {
+    let mut items = vec![];
+    let mut invalid_rows = vec![];
+    for row in rows {
+        match T::from_row(row) {
+            Ok(item) => items.push(item),
+            Err(err) => invalid_rows.push(InvalidRow {
+                id: row.get("id").and_then(|id| id.as_s().ok()).cloned(),
+                error: err.to_string(),
+            }),
+        }
+    }
+    (items, invalid_rows)
+}
 
-    let mut removed = vec![];
+/**
+ * Lists every item in the table, following cursors until all pages are read.
+ * For internal use where the whole table is needed (e.g. finding all subscribers).
+ * Rows that can't be read are logged and left out.
+ */
+pub async fn list_all_items<T: ServerSerialize>(store: &dyn Store, table: &str) -> Result<Vec<T>, Error> {
+    let rows = store.list(table).await?;
+    let (items, invalid_rows) = read_rows(&rows);
+    for invalid in invalid_rows {
+        log::warn!("Skipping invalid row {:?} in {}: {}", invalid.id, table, invalid.error);
+    }
+    Ok(items)
+}
 
+/**
+ * Reads the items with the ids. Items in the trash are treated as missing.
+ */
+pub async fn get_items<T: ServerSerialize>(store: &dyn Store, input: GetRequest, table: &str, access: &RowAccess) -> Result<GetResponse<T>, Error> {
+    for id in &input.ids {
+        access.check_id(Some(id))?;
+    }
+    let mut rows = live_rows(store.get_batch(table, &input.ids).await?);
+    let mut items = vec![];
+    let mut missing = vec![];
     for id in input.ids {
-        let request_map = {
-            let mut map = HashMap::new();
-            map.insert("id".to_string(), AttributeValue::S(id.clone()));
-            map
-        };
+        match rows.remove(&id) {
+            Some(row) => items.push(T::from_row(&row)?),
+            None => missing.push(id),
+        }
+    }
+    Ok(GetResponse { items, missing })
+}
 
-        let config = aws_config::load_from_env().await;
-        let client = Client::new(&config);
-        let table_response = client.delete_item()
-            .table_name(table)
-            .set_key(Some(request_map))
-            .return_values(ReturnValue::AllOld)
-            .send().await;
-        let table_response = table_response?;
-
-        // Read the old member (if any)
-        let old_item = table_response.attributes()
-            .map(|attributes| T::from_row(attributes));
-        let old_item = match old_item {
-            Some(Ok(m)) => Some(m),
-            _ => None,
-        };
-        removed.push(old_item)
+pub async fn delete_items<T: ServerSerialize>(store: &dyn Store, input: DeleteRequest, table: &str, access: &RowAccess, audit: &Audit<'_>) -> Result<DeleteResponse<T>, Error> {
+    for id in &input.ids {
+        access.check_id(Some(id))?;
     }
-    
+    let response: DeleteResponse<T> = match input.all_or_nothing {
+        true => delete_all_or_nothing(store, input.ids, table).await?,
+        false => delete_each(store, input.ids, table).await?,
+    };
+
+    let changes = response.deletes.iter()
+        .filter_map(|delete| delete.removed.as_ref().map(|removed| Change {
+            action: AuditAction::Delete,
+            id: &delete.id,
+            before: Some(removed.into_row()),
+            after: None,
+        }))
+        .collect();
+    audit.record(table, changes).await;
+    Ok(response)
+}
+
+/**
+ * Deletes each item that can be deleted
+ */
+async fn delete_each<T: ServerSerialize>(store: &dyn Store, ids: Vec<String>, table: &str) -> Result<DeleteResponse<T>, Error> {
+    // Read the old items (if any) so they can be returned, and make sure they don't change before deleting
+    let old_rows = live_rows(store.get_batch(table, &ids).await?);
+
+    // Deleted items are kept in the trash, so they can be restored
+    let deleted_at = now();
+    let mut seen = HashSet::new();
+    let duplicate: Vec<bool> = ids.iter().map(|id| old_rows.contains_key(id) && !seen.insert(id)).collect();
+    let mut writes = vec![];
+    for (id, &duplicate) in ids.iter().zip(&duplicate) {
+        if let Some(row) = old_rows.get(id).filter(|_| !duplicate) {
+            writes.push(store.put(table, trash_row(row, deleted_at), Expect::Version(row_version(row))));
+        }
+    }
+    let mut results = stream::iter(writes)
+        .buffered(MAX_CONCURRENT_WRITES)
+        .collect::<Vec<_>>().await
+        .into_iter();
+
+    let deletes = ids.into_iter().zip(duplicate).map(|(id, duplicate)| match old_rows.get(&id) {
+        Some(_) if duplicate => DeleteStatus {
+            error: Some(ItemError::new(DUPLICATE, format!("Item is changed more than once: {}", id))),
+            id,
+            removed: None,
+        },
+        Some(row) => {
+            let error = match results.next() {
+                Some(Ok(WriteResult::Written(_))) => None,
+                Some(Ok(WriteResult::Conflict(_))) => Some(ItemError::new(CONFLICT, "Item was changed by someone else")),
+                Some(Err(err)) => Some(ApiError::from_error(err).to_item_error()),
+                None => Some(ItemError::new(UPSTREAM, "Item was not deleted")),
+            };
+            DeleteStatus {
+                removed: T::from_row(row).ok().filter(|_| error.is_none()),
+                id,
+                error,
+            }
+        },
+        None => DeleteStatus {
+            id,
+            removed: None,
+            error: None,
+        },
+    }).collect();
+
     Ok(DeleteResponse {
-        removed,
+        deletes,
     })
 }
 
-pub async fn update_items<T:ServerSerialize>(input: UpdateRequest<T>, table_name: &str) -> Result<UpdateResponse<T>, Error> {
-    let mut results = vec![];
+/**
+ * Deletes every item in one transaction, or none of them
+ */
+async fn delete_all_or_nothing<T: ServerSerialize>(store: &dyn Store, ids: Vec<String>, table: &str) -> Result<DeleteResponse<T>, Error> {
+    check_transaction(ids.iter().map(|id| &id[..]))?;
+
+    // Read the items first so they can be returned, and make sure they don't change before deleting
+    let old_rows = live_rows(store.get_batch(table, &ids).await?);
+    let deleted_at = now();
+    let writes: Vec<TransactWrite> = ids.iter()
+        .filter_map(|id| old_rows.get(id))
+        .map(|row| TransactWrite::Put {
+            row: trash_row(row, deleted_at),
+            expect: Expect::Version(row_version(row)),
+        })
+        .collect();
+
+    let result = match writes.is_empty() {
+        true => TransactResult::Written,
+        false => store.transact(table, &writes).await?,
+    };
+    let mut errors = match result {
+        TransactResult::Written => vec![None; writes.len()],
+        TransactResult::Cancelled(reasons) => transaction_errors(writes.len(), &reasons),
+    }.into_iter();
+
+    let deletes = ids.into_iter().map(|id| match old_rows.get(&id) {
+        Some(row) => {
+            let error = errors.next().flatten();
+            DeleteStatus {
+                removed: T::from_row(row).ok().filter(|_| error.is_none()),
+                id,
+                error,
+            }
+        },
+        None => DeleteStatus {
+            id,
+            removed: None,
+            error: None,
+        },
+    }).collect();
+
+    Ok(DeleteResponse {
+        deletes,
+    })
+}
+
+/**
+ * Lists the items in the trash, and when they can be purged
+ */
+pub async fn list_trash<T: ServerSerialize>(store: &dyn Store, input: ListTrashRequest, table: &str, retention: Duration) -> Result<ListTrashResponse<T>, Error> {
+    let scan = Scan {
+        limit: input.limit,
+        cursor: input.cursor,
+        trashed: true,
+        ..Default::default()
+    };
+    let page = store.scan(table, &scan).await?;
+
+    let mut items = vec![];
+    for row in &page.rows {
+        match (T::from_row(row), row_deleted_at(row)) {
+            (Ok(item), Some(deleted_at)) => items.push(TrashedItem {
+                item,
+                deleted_at,
+                purge_after: deleted_at + retention.as_secs(),
+            }),
+            (Err(err), _) => log::warn!("Skipping invalid row {:?} in {}: {}", row_id(row), table, err),
+            (Ok(_), None) => log::warn!("Skipping row {:?} in {} with an invalid {}", row_id(row), table, DELETED_AT),
+        }
+    }
+
+    Ok(ListTrashResponse {
+        items,
+        next_cursor: page.next_cursor,
+    })
+}
+
+/**
+ * Takes items out of the trash
+ */
+pub async fn restore_items<T: ServerSerialize>(store: &dyn Store, input: RestoreRequest, table: &str, audit: &Audit<'_>) -> Result<RestoreResponse<T>, Error> {
+    let stored = store.get_batch(table, &input.ids).await?;
+    let update = RowUpdate {
+        remove: vec![DELETED_AT.to_string()],
+        ..Default::default()
+    };
+
+    let mut restores = vec![];
+    for id in input.ids {
+        let row = match stored.get(&id).filter(|row| row.contains_key(DELETED_AT)) {
+            Some(row) => row,
+            None => {
+                let error = ItemError::new(NOT_FOUND, format!("No item in the trash with id: {}", id));
+                restores.push(RestoreStatus { id, restored: None, error: Some(error) });
+                continue;
+            },
+        };
+        let status = match store.update(table, &id, &update, Expect::Version(row_version(row))).await {
+            Ok(WriteResult::Written(old_row)) => {
+                let mut new_row = old_row.unwrap_or_default();
+                update.apply(&mut new_row);
+                RestoreStatus {
+                    restored: T::from_row(&new_row).ok(),
+                    id,
+                    error: None,
+                }
+            },
+            Ok(WriteResult::Conflict(_)) => {
+                let error = ItemError::new(CONFLICT, format!("Item {} was changed by someone else", id));
+                RestoreStatus { id, restored: None, error: Some(error) }
+            },
+            Err(err) => RestoreStatus {
+                id,
+                restored: None,
+                error: Some(ApiError::from_error(err).to_item_error()),
+            },
+        };
+        restores.push(status);
+    }
+
+    let changes = restores.iter()
+        .filter_map(|restore| restore.restored.as_ref().map(|restored| Change {
+            action: AuditAction::Restore,
+            id: &restore.id,
+            before: None,
+            after: Some(restored.into_row()),
+        }))
+        .collect();
+    audit.record(table, changes).await;
+
+    Ok(RestoreResponse {
+        restores,
+    })
+}
+
+/**
+ * Permanently removes items from the trash. Without ids, removes every item that has been in the trash for longer
+ * than the retention.
+ */
+pub async fn purge_items(store: &dyn Store, input: PurgeRequest, table: &str, retention: Duration, audit: &Audit<'_>) -> Result<PurgeResponse, Error> {
+    let scan = Scan {
+        fields: Some(vec!["id".to_string(), DELETED_AT.to_string()]),
+        trashed: true,
+        ..Default::default()
+    };
+    let trashed = store.scan_all(table, &scan).await?;
+
+    let cutoff = now().saturating_sub(retention.as_secs());
+    let ids: Vec<String> = trashed.iter()
+        .filter(|row| match input.ids.is_empty() {
+            true => row_deleted_at(row).is_some_and(|deleted_at| deleted_at <= cutoff),
+            false => row_id(row).is_some_and(|id| input.ids.contains(&id)),
+        })
+        .filter_map(row_id)
+        .collect();
+    let writes = ids.iter().map(|id| BatchWrite::Delete(id.clone())).collect();
+    let errors = write_batches(store, table, writes).await;
+
+    let mut purged = vec![];
+    for (id, error) in ids.into_iter().zip(errors) {
+        match error {
+            None => purged.push(id),
+            Some(error) => log::warn!("Failed to purge {} from {}: {}", id, table, error.message),
+        }
+    }
+    let changes = purged.iter()
+        .map(|id| Change { action: AuditAction::Purge, id, before: None, after: None })
+        .collect();
+    audit.record(table, changes).await;
+
+    Ok(PurgeResponse {
+        purged,
+    })
+}
+
+/**
+ * Fills in the lookup attributes (see `ServerSerialize::LOOKUPS`) of rows written before the lookup existed,
+ * including rows in the trash. Rows changed while it runs are skipped, since writing them filled them in.
+ * Returns how many rows were filled in.
+ */
+pub async fn backfill_lookups<T: ServerSerialize>(store: &dyn Store, table: &str) -> Result<usize, Error> {
+    let mut filled = 0;
+    for trashed in [false, true] {
+        for row in store.scan_all(table, &Scan { trashed, ..Default::default() }).await? {
+            let mut update = RowUpdate::default();
+            for (field, lookup) in T::LOOKUPS {
+                if let Some(value) = row.get(*field).and_then(|value| value.as_s().ok()).filter(|_| !row.contains_key(*lookup)) {
+                    write_lookup(&mut update.set, lookup, value);
+                }
+            }
+            if update.set.is_empty() {
+                continue;
+            }
+            let id = row_id(&row).unwrap_or_default();
+            if let WriteResult::Written(_) = store.update(table, &id, &update, Expect::Version(row_version(&row))).await? {
+                filled += 1;
+            }
+        }
+    }
+    Ok(filled)
+}
+
+/**
+ * Leaves out rows that are in the trash
+ */
+fn live_rows(mut rows: HashMap<String, Row>) -> HashMap<String, Row> {
+    rows.retain(|_, row| !row.contains_key(DELETED_AT));
+    rows
+}
+
+/**
+ * Marks a copy of the row as in the trash, bumping its version
+ */
+fn trash_row(row: &Row, deleted_at: u64) -> Row {
+    let mut row = row.clone();
+    let version = row_version(&row).unwrap_or(0) + 1;
+    row.insert(VERSION.to_string(), AttributeValue::N(version.to_string()));
+    row.insert(DELETED_AT.to_string(), AttributeValue::N(deleted_at.to_string()));
+    row
+}
+
+fn row_id(row: &Row) -> Option<String> {
+    row.get("id").and_then(|id| id.as_s().ok()).cloned()
+}
+
+/**
+ * The current time in seconds since the unix epoch
+ */
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+/**
+ * Writes the items, creating those without an id.
+ * Every item is validated first, so an invalid item fails the whole request.
+ */
+pub async fn update_items<T: ServerSerialize + Validate>(store: &dyn Store, input: UpdateRequest<T>, table_name: &str, ids: IdStrategy, context: &ValidationContext, access: &RowAccess, audit: &Audit<'_>) -> Result<UpdateResponse<T>, Error> {
+    let paths = (0..).map(|i| format!("values[{}]", i));
+    check_valid(paths.zip(input.values.iter()), context)?;
+    if ids == IdStrategy::Given {
+        let missing: Vec<FieldError> = input.values.iter().enumerate()
+            .filter(|(_, item)| item.id().is_none())
+            .map(|(i, _)| FieldError::new("id", "An id must be given").within(&format!("values[{}]", i)))
+            .collect();
+        if !missing.is_empty() {
+            return Err(ApiError::invalid_fields(missing).into());
+        }
+    }
+    access.check_items(store, table_name, &input.values).await?;
+    let paths = (0..).map(|i| format!("values[{}]", i));
+    check_lookups(store, table_name, paths.zip(input.values.iter())).await?;
+
+    let mut items = vec![];
 
     for mut item in input.values {
 
         // If no id assigned, assign one
-        if item.id() == None {
-            let id_time = chrono::Utc::now();
-            let id_random = rand::thread_rng().gen::<u32>();
-            let mut id_string = id_time.format("%Y-%m-%d-%H:%M:%S-").to_string();
-            id_string.push_str(&id_random.to_string());
-            item.set_id(id_string);
+        let is_new = item.id().is_none();
+        if let Some(id) = ids.new_id().filter(|_| is_new) {
+            item.set_id(id);
         };
+        items.push((item, is_new));
+    }
 
-        // Put the member in dynamodb
-        let config = aws_config::load_from_env().await;
-        let client = Client::new(&config);
-        let table_response = client.put_item()
-            .table_name(table_name)
-            .set_item(Some(item.into_row()))
-            .return_values(ReturnValue::AllOld)
-            .send().await;
-        let table_response = table_response?;
-
-        // Read the old member (if any)
-        let old_item = table_response.attributes()
-            .map(|attributes| T::from_row(attributes));
-        let old_item = match old_item {
-            Some(Ok(m)) => Some(m),
-            _ => None,
-        };
+    let response = match input.all_or_nothing {
+        true => update_all_or_nothing(store, items, table_name).await?,
+        false => update_each(store, items, table_name).await?,
+    };
+    audit.record(table_name, update_changes(&response)).await;
+    Ok(response)
+}
+
+/**
+ * Writes each item that can be written
+ */
+async fn update_each<T: ServerSerialize>(store: &dyn Store, mut items: Vec<(T, bool)>, table_name: &str) -> Result<UpdateResponse<T>, Error> {
+    // Read the stored versions, and the items so they can be returned
+    let ids: Vec<String> = items.iter()
+        .filter(|(_, is_new)| !is_new)
+        .map(|(item, _)| item.id().unwrap_or_default().to_string())
+        .collect();
+    let stored = store.get_batch(table_name, &ids).await?;
+
+    // Stored items are written one at a time, so a change can't go over the item being put in the trash.
+    // New items can't be in the trash, so they can be written in batches.
+    let mut seen = HashSet::new();
+    let writes: Vec<UpdateWrite> = items.iter().map(|(item, is_new)| {
+        let id = item.id().unwrap_or_default();
+        match (stored.get(id), item.version()) {
+            (Some(row), _) if row.contains_key(DELETED_AT) => UpdateWrite::Trashed,
+            (_, Some(_)) => UpdateWrite::Versioned,
+            _ if *is_new => UpdateWrite::Batched,
+            _ if !seen.insert(id) => UpdateWrite::Duplicate,
+            _ => UpdateWrite::Live,
+        }
+    }).collect();
+    let mut checked_writes = vec![];
+    for ((item, _), write) in items.iter_mut().zip(&writes) {
+        match write {
+            UpdateWrite::Versioned => {
+                let expected_version = item.version().unwrap_or_default();
+                checked_writes.push(put_expected(store, table_name, item, Expect::Version(Some(expected_version)), expected_version));
+            },
+            UpdateWrite::Live => {
+                let stored_version = stored.get(item.id().unwrap_or_default()).and_then(row_version).unwrap_or(0);
+                checked_writes.push(put_expected(store, table_name, item, Expect::Live, stored_version));
+            },
+            _ => {},
+        }
+    }
+    let mut checked = stream::iter(checked_writes)
+        .buffered(MAX_CONCURRENT_WRITES)
+        .collect::<Vec<_>>().await
+        .into_iter();
 
-        results.push(UpdateStatus {
-            replaced: old_item,
-            current: item,
-        });
+    let mut unchecked_writes = vec![];
+    for ((item, _), write) in items.iter_mut().zip(&writes) {
+        if *write == UpdateWrite::Batched {
+            item.set_version(1);
+            unchecked_writes.push(BatchWrite::Put(item.into_row()));
+        }
+    }
+    let mut unchecked = write_batches(store, table_name, unchecked_writes).await.into_iter();
+
+    let mut results = vec![];
+    for ((item, _), write) in items.into_iter().zip(writes) {
+        let id = item.id().unwrap_or_default().to_string();
+        let written = match write {
+            UpdateWrite::Versioned | UpdateWrite::Live => match checked.next() {
+                Some(Ok(result)) => Ok(result),
+                Some(Err(err)) => Err(ApiError::from_error(err).to_item_error()),
+                None => Err(ItemError::new(UPSTREAM, "Item was not written")),
+            },
+            UpdateWrite::Batched => match unchecked.next().flatten() {
+                None => Ok(WriteResult::Written(None)),
+                Some(error) => Err(error),
+            },
+            UpdateWrite::Trashed => Err(trashed_error(&id)),
+            UpdateWrite::Duplicate => Err(ItemError::new(DUPLICATE, format!("Item is changed more than once: {}", id))),
+        };
+        let status = match written {
+            Ok(WriteResult::Written(old_row)) => UpdateStatus {
+                replaced: old_row.and_then(|row| T::from_row(&row).ok()),
+                current: item,
+                conflict: None,
+                error: None,
+            },
+            Ok(WriteResult::Conflict(server_row)) if write == UpdateWrite::Versioned => {
+                let expected_version = item.version().unwrap_or_default();
+                conflict_status(item, expected_version, server_row)
+            },
+            // Only the trash stops a write that expects the item isn't in it
+            Ok(WriteResult::Conflict(_)) => UpdateStatus {
+                replaced: None,
+                current: item,
+                conflict: None,
+                error: Some(trashed_error(&id)),
+            },
+            Err(error) => UpdateStatus {
+                replaced: None,
+                current: item,
+                conflict: None,
+                error: Some(error),
+            },
+        };
+        results.push(status);
     }
 
     Ok(UpdateResponse {
         updates: results,
     })
-}
\ No newline at end of file
+}
+
+/**
+ * How an item is written by `update_each`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateWrite {
+    /** Only if the stored item still has the version the item was read at */
+    Versioned,
+    /** Over the stored item, unless it is put in the trash first */
+    Live,
+    /** In a batch, since it is new */
+    Batched,
+    /** Not at all, since the stored item is in the trash */
+    Trashed,
+    /** Not at all, since an earlier item has the same id */
+    Duplicate,
+}
+
+fn trashed_error(id: &str) -> ItemError {
+    ItemError::new(NOT_FOUND, format!("Item is in the trash, restore it first: {}", id))
+}
+
+/**
+ * The changes made by the items that were written
+ */
+fn update_changes<T: ServerSerialize>(response: &UpdateResponse<T>) -> Vec<Change<'_>> {
+    response.updates.iter()
+        .filter(|update| update.error.is_none())
+        .map(|update| Change {
+            action: match update.replaced {
+                Some(_) => AuditAction::Update,
+                None => AuditAction::Create,
+            },
+            id: update.current.id().unwrap_or_default(),
+            before: update.replaced.as_ref().map(|replaced| replaced.into_row()),
+            after: Some(update.current.into_row()),
+        })
+        .collect()
+}
+
+/**
+ * Writes the item at the version after the stored one, only if the stored row is as expected
+ */
+async fn put_expected<T: ServerSerialize>(store: &dyn Store, table_name: &str, item: &mut T, expect: Expect, stored_version: u64) -> Result<WriteResult, Error> {
+    let version = item.version();
+    item.set_version(stored_version + 1);
+    let result = store.put(table_name, item.into_row(), expect).await;
+    if !matches!(result, Ok(WriteResult::Written(_))) {
+        if let Some(version) = version {
+            item.set_version(version);
+        }
+    }
+    result
+}
+
+/**
+ * Makes the writes in batches, sending a few batches at once.
+ * Returns the error (if any) for each write.
+ */
+pub(crate) async fn write_batches(store: &dyn Store, table: &str, writes: Vec<BatchWrite>) -> Vec<Option<ItemError>> {
+    let mut errors = vec![None; writes.len()];
+
+    // A batch can't write the same row twice, and writes in different batches could happen in any order
+    let mut seen = HashSet::new();
+    let mut indexes = vec![];
+    for (i, write) in writes.iter().enumerate() {
+        let id = write.id().unwrap_or_default();
+        if seen.insert(id) {
+            indexes.push(i);
+        } else {
+            errors[i] = Some(ItemError::new(DUPLICATE, format!("Item is changed more than once: {}", id)));
+        }
+    }
+
+    let mut batches = vec![];
+    for chunk in indexes.chunks(MAX_BATCH_WRITES) {
+        let batch: Vec<BatchWrite> = chunk.iter().map(|&i| writes[i].clone()).collect();
+        batches.push(write_batch(store, table, chunk, batch));
+    }
+    let results: Vec<_> = stream::iter(batches)
+        .buffer_unordered(MAX_CONCURRENT_WRITES)
+        .collect().await;
+
+    for (chunk, result) in results {
+        let result = result.map_err(ApiError::from_error);
+        for &i in chunk {
+            errors[i] = match &result {
+                Ok(unprocessed) if unprocessed.iter().any(|id| writes[i].id() == Some(id)) => {
+                    Some(ApiError::RateLimited("The store was too busy to make the change".to_string()).to_item_error())
+                },
+                Ok(_) => None,
+                Err(err) => Some(err.to_item_error()),
+            };
+        }
+    }
+    errors
+}
+
+/**
+ * Makes a batch of writes, keeping track of which writes were in the batch
+ */
+async fn write_batch<'a>(store: &dyn Store, table: &str, indexes: &'a [usize], batch: Vec<BatchWrite>) -> (&'a [usize], Result<Vec<String>, Error>) {
+    (indexes, store.write_batch(table, &batch).await)
+}
+
+/**
+ * Writes every item in one transaction, or none of them
+ */
+async fn update_all_or_nothing<T: ServerSerialize>(store: &dyn Store, items: Vec<(T, bool)>, table_name: &str) -> Result<UpdateResponse<T>, Error> {
+    check_transaction(items.iter().map(|(item, _)| item.id().unwrap_or_default()))?;
+
+    // Read the items first so they can be returned, and make sure they don't change before writing
+    let ids: Vec<String> = items.iter()
+        .filter(|(_, is_new)| !is_new)
+        .map(|(item, _)| item.id().unwrap_or_default().to_string())
+        .collect();
+    let mut stored_rows = store.get_batch(table_name, &ids).await?;
+    let stored: Vec<Option<Row>> = items.iter().map(|(item, _)| stored_rows.remove(item.id().unwrap_or_default())).collect();
+
+    // Items that expect an old version can't be written, so don't bother trying
+    let stale: Vec<bool> = items.iter().zip(&stored)
+        .map(|((item, _), row)| item.version().is_some_and(|version| row.as_ref().and_then(row_version) != Some(version)))
+        .collect();
+    // Items in the trash have to be restored before they can be changed
+    let trashed: Vec<bool> = stored.iter().map(|row| row.as_ref().is_some_and(|row| row.contains_key(DELETED_AT))).collect();
+    let result = if stale.contains(&true) {
+        let reasons = stale.iter().map(|&stale| Some(CONDITION_FAILED.to_string()).filter(|_| stale)).collect();
+        TransactResult::Cancelled(reasons)
+    } else if trashed.contains(&true) {
+        let reasons = trashed.iter().map(|&trashed| Some(TRASHED.to_string()).filter(|_| trashed)).collect();
+        TransactResult::Cancelled(reasons)
+    } else {
+        let mut writes = vec![];
+        for ((item, _), row) in items.iter().zip(&stored) {
+            let stored_version = row.as_ref().and_then(row_version);
+            let mut row = item.into_row();
+            row.insert(VERSION.to_string(), AttributeValue::N((stored_version.unwrap_or(0) + 1).to_string()));
+            writes.push(TransactWrite::Put { row, expect: Expect::Version(stored_version) });
+        }
+        store.transact(table_name, &writes).await?
+    };
+
+    let errors = match &result {
+        TransactResult::Written => vec![None; items.len()],
+        TransactResult::Cancelled(reasons) => transaction_errors(items.len(), reasons),
+    };
+    let updates = items.into_iter().zip(stored).zip(errors).map(|(((mut item, _), row), error)| {
+        let expected_version = item.version();
+        match error {
+            None => {
+                item.set_version(row.as_ref().and_then(row_version).unwrap_or(0) + 1);
+                UpdateStatus {
+                    replaced: row.and_then(|row| T::from_row(&row).ok()),
+                    current: item,
+                    conflict: None,
+                    error: None,
+                }
+            },
+            Some(error) if error.code == CONFLICT && expected_version.is_some() => {
+                conflict_status(item, expected_version.unwrap_or_default(), row)
+            },
+            Some(error) => UpdateStatus {
+                replaced: None,
+                current: item,
+                conflict: None,
+                error: Some(error),
+            },
+        }
+    }).collect();
+
+    Ok(UpdateResponse {
+        updates,
+    })
+}
+
+/**
+ * Creates the status of an item that wasn't written because the stored row had a different version
+ */
+fn conflict_status<T: ServerSerialize>(item: T, expected_version: u64, server_row: Option<Row>) -> UpdateStatus<T> {
+    let message = format!("Item {} was changed by someone else", item.id().unwrap_or_default());
+    UpdateStatus {
+        replaced: None,
+        current: item,
+        conflict: Some(VersionConflict {
+            expected_version,
+            server_copy: server_row.and_then(|row| T::from_row(&row).ok()),
+        }),
+        error: Some(ItemError::new(CONFLICT, message)),
+    }
+}
+
+/**
+ * Checks the writes can be made in one transaction
+ */
+fn check_transaction<'a>(ids: impl Iterator<Item = &'a str>) -> Result<(), ApiError> {
+    let mut seen = vec![];
+    for id in ids {
+        if seen.contains(&id) {
+            return Err(ApiError::Validation(format!("Item is changed more than once: {}", id)));
+        }
+        seen.push(id);
+    }
+    if seen.len() > MAX_TRANSACTION_WRITES {
+        let msg = format!("At most {} items can be changed with all_or_nothing", MAX_TRANSACTION_WRITES);
+        return Err(ApiError::Validation(msg));
+    }
+    Ok(())
+}
+
+/**
+ * Converts the reasons a transaction was cancelled into an error for each item
+ */
+fn transaction_errors(count: usize, reasons: &[Option<String>]) -> Vec<Option<ItemError>> {
+    (0..count).map(|i| Some(match reasons.get(i).cloned().flatten() {
+        Some(reason) if reason == CONDITION_FAILED => ItemError::new(CONFLICT, "Item was changed by someone else"),
+        Some(reason) if reason == TRASHED => ItemError::new(NOT_FOUND, "Item is in the trash, restore it first"),
+        Some(reason) => ItemError::new(ABORTED, format!("Transaction cancelled: {}", reason)),
+        None => ItemError::new(ABORTED, "Not changed because another item failed"),
+    })).collect()
+}
+
+/**
+ * Changes some fields of stored items, without needing to send the whole item.
+ * List fields are added to and removed from in place, so patches to the same list don't overwrite each other.
+ * Every patch is checked before any are written, so an invalid patch fails the whole request.
+ */
+pub async fn patch_items<T: ServerSerialize + Validate>(store: &dyn Store, input: PatchRequest, table_name: &str, context: &ValidationContext, access: &RowAccess, audit: &Audit<'_>) -> Result<UpdateResponse<T>, Error> {
+    // Check the patches leave valid items before writing any
+    let mut checked = vec![];
+    for patch in input.patches {
+        access.check_id(Some(&patch.id))?;
+        let mut update = row_update(&patch)?;
+        let patched = |field: &&String| patch.set.contains_key(*field) || patch.unset.contains(field)
+            || patch.add.contains_key(*field) || patch.remove.contains_key(*field);
+        if let Some(field) = access.locked_fields().iter().find(patched) {
+            return Err(ApiError::Forbidden(format!("Field can only be changed by an admin: {}", field)).into());
+        }
+        let lookups: Vec<String> = T::LOOKUPS.iter().map(|(_, lookup)| lookup.to_string()).collect();
+        if let Some(lookup) = lookups.iter().find(patched) {
+            return Err(ApiError::Validation(format!("Field can't be patched: {}", lookup)).into());
+        }
+        let mut preview = match store.get(table_name, &patch.id).await? {
+            Some(row) if !row.contains_key(DELETED_AT) => row,
+            _ => return Err(ApiError::NotFound(format!("No item with id: {}", patch.id)).into()),
+        };
+        update.apply(&mut preview);
+        let preview = T::from_row(&preview).map_err(|err| {
+            ApiError::Validation(format!("Patch makes item {} invalid: {}", patch.id, err))
+        })?;
+        // Keep lookups in step with the fields they copy
+        let row = preview.into_row();
+        for lookup in lookups {
+            match row.get(&lookup) {
+                Some(value) => { update.set.insert(lookup, value.clone()); },
+                None => update.remove.push(lookup),
+            }
+        }
+        checked.push((patch, update, preview));
+    }
+    let paths = (0..).map(|i| format!("patches[{}]", i));
+    check_valid(paths.zip(checked.iter().map(|(_, _, preview)| preview)), context)?;
+    let paths = (0..).map(|i| format!("patches[{}]", i));
+    check_lookups(store, table_name, paths.zip(checked.iter().map(|(_, _, preview)| preview))).await?;
+
+    let mut results = vec![];
+    for (patch, update, preview) in checked {
+        let expect = match patch.expected_version {
+            Some(version) => Expect::Version(Some(version)),
+            None => Expect::Live,
+        };
+        let status = match store.update(table_name, &patch.id, &update, expect).await {
+            Ok(WriteResult::Written(old_row)) => {
+                let old_row = old_row.unwrap_or_default();
+                let mut new_row = old_row.clone();
+                update.apply(&mut new_row);
+                UpdateStatus {
+                    replaced: T::from_row(&old_row).ok(),
+                    current: T::from_row(&new_row).unwrap_or(preview),
+                    conflict: None,
+                    error: None,
+                }
+            },
+            Ok(WriteResult::Conflict(None)) => UpdateStatus {
+                replaced: None,
+                current: preview,
+                conflict: None,
+                error: Some(ItemError::new(NOT_FOUND, format!("No item with id: {}", patch.id))),
+            },
+            Ok(WriteResult::Conflict(Some(server_row))) if patch.expected_version.is_some() => {
+                conflict_status(preview, patch.expected_version.unwrap_or_default(), Some(server_row))
+            },
+            Ok(WriteResult::Conflict(_)) => UpdateStatus {
+                replaced: None,
+                current: preview,
+                conflict: None,
+                error: Some(trashed_error(&patch.id)),
+            },
+            Err(err) => UpdateStatus {
+                replaced: None,
+                current: preview,
+                conflict: None,
+                error: Some(ApiError::from_error(err).to_item_error()),
+            },
+        };
+        results.push(status);
+    }
+
+    let response = UpdateResponse {
+        updates: results,
+    };
+    audit.record(table_name, update_changes(&response)).await;
+    Ok(response)
+}
+
+/**
+ * Checks every item is valid, reporting the problems with all of them at once
+ */
+fn check_valid<'a, T: Validate + 'a>(items: impl Iterator<Item = (String, &'a T)>, context: &ValidationContext) -> Result<(), ApiError> {
+    let errors: Vec<FieldError> = items
+        .flat_map(|(path, item)| item.validate(context).into_iter().map(move |error| error.within(&path)))
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ApiError::invalid_fields(errors)),
+    }
+}
+
+/**
+ * Checks no two items, and no item and another stored row, share a lookup value (e.g. two members with one email).
+ * Rows written at the same time as the check could still share one.
+ */
+async fn check_lookups<'a, T: ServerSerialize + 'a>(store: &dyn Store, table_name: &str, items: impl Iterator<Item = (String, &'a T)>) -> Result<(), Error> {
+    if T::LOOKUPS.is_empty() {
+        return Ok(());
+    }
+    // The ids of the items using each lookup value (None for new items), and where each item uses them
+    let mut requested: HashMap<(&'static str, String), Vec<Option<String>>> = HashMap::new();
+    let mut uses = vec![];
+    for (path, item) in items {
+        let row = item.into_row();
+        for (field, lookup) in T::LOOKUPS {
+            if let Some(value) = row.get(*lookup).and_then(|value| value.as_s().ok()) {
+                requested.entry((*lookup, value.clone())).or_default().push(item.id().map(str::to_string));
+                uses.push((path.clone(), *field, (*lookup, value.clone()), item.id().map(str::to_string)));
+            }
+        }
+    }
+
+    // The ids of the stored rows using them
+    let rows: Vec<Row> = if requested.len() > MAX_LOOKUP_QUERIES {
+        let mut fields = vec!["id".to_string()];
+        fields.extend(T::LOOKUPS.iter().map(|(_, lookup)| lookup.to_string()));
+        store.scan_all(table_name, &Scan { fields: Some(fields), ..Default::default() }).await?
+    } else {
+        let keys: Vec<(&'static str, String)> = requested.keys().cloned().collect();
+        let mut finds = vec![];
+        for (lookup, value) in &keys {
+            finds.push(store.find(table_name, lookup, value));
+        }
+        let found: Vec<Vec<Row>> = stream::iter(finds).buffer_unordered(MAX_CONCURRENT_WRITES).collect::<Vec<_>>().await
+            .into_iter().collect::<Result<_, _>>()?;
+        found.into_iter().flatten().collect()
+    };
+    let mut stored: HashMap<(&'static str, String), Vec<String>> = HashMap::new();
+    for row in &rows {
+        for (_, lookup) in T::LOOKUPS {
+            if let (Some(value), Some(id)) = (row.get(*lookup).and_then(|value| value.as_s().ok()), row_id(row)) {
+                stored.entry((*lookup, value.clone())).or_default().push(id);
+            }
+        }
+    }
+
+    // Writing the same item twice is reported as a duplicate when it is written, so only other items count
+    let taken = |key: &(&'static str, String), id: &Option<String>| {
+        let others = requested[key].iter().filter(|other| other.is_none() || *other != id).count();
+        others > usize::from(id.is_none()) || stored.get(key).is_some_and(|ids| ids.iter().any(|other| Some(other) != id.as_ref()))
+    };
+    let errors: Vec<FieldError> = uses.into_iter()
+        .filter(|(_, _, key, id)| taken(key, id))
+        .map(|(path, field, _, _)| FieldError::new(field, "Another item already has this value").within(&path))
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ApiError::invalid_fields(errors).into()),
+    }
+}
+
+/**
+ * Converts a patch into the changes to make to its row
+ */
+fn row_update(patch: &Patch) -> Result<RowUpdate, ApiError> {
+    let fields = patch.set.keys()
+        .chain(patch.unset.iter())
+        .chain(patch.add.keys())
+        .chain(patch.remove.keys());
+    let mut seen = vec![];
+    for field in fields {
+        if field == "id" || field == VERSION {
+            return Err(ApiError::Validation(format!("Field can't be patched: {}", field)));
+        }
+        if seen.contains(&field) {
+            return Err(ApiError::Validation(format!("Field is patched more than once: {}", field)));
+        }
+        seen.push(field);
+    }
+
+    let mut update = RowUpdate {
+        remove: patch.unset.clone(),
+        ..Default::default()
+    };
+    for (field, value) in &patch.set {
+        match value {
+            PatchValue::Bool(b) => { update.set.insert(field.clone(), AttributeValue::Bool(*b)); },
+            PatchValue::Integer(n) => { update.set.insert(field.clone(), AttributeValue::N(n.to_string())); },
+            PatchValue::Decimal(n) => { update.set.insert(field.clone(), AttributeValue::N(n.to_string())); },
+            PatchValue::Text(s) => { update.set.insert(field.clone(), AttributeValue::S(s.clone())); },
+            // DynamoDB can't store an empty set, so an empty list removes the field
+            PatchValue::List(list) if list.is_empty() => update.remove.push(field.clone()),
+            PatchValue::List(list) => { update.set.insert(field.clone(), AttributeValue::Ss(list.clone())); },
+        }
+    }
+    for (field, values) in &patch.add {
+        if !values.is_empty() {
+            update.add.insert(field.clone(), values.clone());
+        }
+    }
+    for (field, values) in &patch.remove {
+        if !values.is_empty() {
+            update.delete.insert(field.clone(), values.clone());
+        }
+    }
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use app_core::{Member, api::{UpdateRequest, UpdateResponse, DeleteResponse, ListRequest, GetRequest, DeleteRequest, ListTrashRequest, RestoreRequest, PurgeRequest, Filter, FilterOp, Sort, PatchRequest, Patch, PatchValue}, validate::ValidationContext, PhoneNumber, Role};
+
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use async_trait::async_trait;
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use lambda_http::Error;
+
+    use crate::ApiError;
+    use crate::audit::Audit;
+    use crate::store::{MemoryStore, Store, Expect, key, Row, RowUpdate, Page, Scan, WriteResult, TransactWrite, TransactResult, BatchWrite};
+    use super::{IdStrategy, RowAccess, update_items, list_items, get_items, list_all_items, delete_items, patch_items, list_trash, restore_items, purge_items, backfill_lookups,
+        CONFLICT, ABORTED, DUPLICATE, NOT_FOUND};
+
+    /**
+     * A memory store that takes as long as a network request to answer, and counts the requests made
+     */
+    #[derive(Default)]
+    struct SlowStore {
+        inner: MemoryStore,
+        requests: AtomicUsize,
+    }
+
+    impl SlowStore {
+        async fn request(&self) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[async_trait]
+    impl Store for SlowStore {
+        async fn scan(&self, table: &str, scan: &Scan) -> Result<Page, Error> {
+            self.request().await;
+            self.inner.scan(table, scan).await
+        }
+
+        async fn get(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+            self.request().await;
+            self.inner.get(table, id).await
+        }
+
+        async fn get_batch(&self, table: &str, ids: &[String]) -> Result<HashMap<String, Row>, Error> {
+            self.request().await;
+            self.inner.get_batch(table, ids).await
+        }
+
+        async fn put(&self, table: &str, row: Row, expect: Expect) -> Result<WriteResult, Error> {
+            self.request().await;
+            self.inner.put(table, row, expect).await
+        }
+
+        async fn update(&self, table: &str, id: &str, update: &RowUpdate, expect: Expect) -> Result<WriteResult, Error> {
+            self.request().await;
+            self.inner.update(table, id, update, expect).await
+        }
+
+        async fn delete(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+            self.request().await;
+            self.inner.delete(table, id).await
+        }
+
+        async fn transact(&self, table: &str, writes: &[TransactWrite]) -> Result<TransactResult, Error> {
+            self.request().await;
+            self.inner.transact(table, writes).await
+        }
+
+        async fn write_batch(&self, table: &str, writes: &[BatchWrite]) -> Result<Vec<String>, Error> {
+            self.request().await;
+            self.inner.write_batch(table, writes).await
+        }
+    }
+
+    fn audit(store: &dyn Store) -> Audit<'_> {
+        Audit::new(store, "audit", "tester")
+    }
+
+    fn member(name: &str) -> Member {
+        Member {
+            id: None,
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+            address: None,
+            mobile: None,
+            subscriptions: vec!["news".to_string()],
+            role: Role::Member,
+            version: None,
+        }
+    }
+
+    /** Writes the members with timestamped ids, as a caller who can change every member */
+    async fn update(store: &dyn Store, values: Vec<Member>) -> Result<UpdateResponse<Member>, Error> {
+        update_with(store, UpdateRequest { values, all_or_nothing: false }, IdStrategy::Timestamped, &RowAccess::All).await
+    }
+
+    /** Like `update`, but writes every member or none of them */
+    async fn update_all(store: &dyn Store, values: Vec<Member>) -> Result<UpdateResponse<Member>, Error> {
+        update_with(store, UpdateRequest { values, all_or_nothing: true }, IdStrategy::Timestamped, &RowAccess::All).await
+    }
+
+    /** Writes the members, auditing to a store of its own so the requests to `store` can be counted */
+    async fn update_with(store: &dyn Store, request: UpdateRequest<Member>, ids: IdStrategy, access: &RowAccess) -> Result<UpdateResponse<Member>, Error> {
+        let audit_store = MemoryStore::new();
+        update_items(store, request, "members", ids, &ValidationContext::default(), access, &audit(&audit_store)).await
+    }
+
+    async fn delete(store: &dyn Store, ids: Vec<String>) -> Result<DeleteResponse<Member>, Error> {
+        delete_with(store, DeleteRequest { ids, all_or_nothing: false }, &RowAccess::All).await
+    }
+
+    async fn delete_all(store: &dyn Store, ids: Vec<String>) -> Result<DeleteResponse<Member>, Error> {
+        delete_with(store, DeleteRequest { ids, all_or_nothing: true }, &RowAccess::All).await
+    }
+
+    async fn delete_with(store: &dyn Store, request: DeleteRequest, access: &RowAccess) -> Result<DeleteResponse<Member>, Error> {
+        let audit_store = MemoryStore::new();
+        delete_items(store, request, "members", access, &audit(&audit_store)).await
+    }
+
+    #[tokio::test]
+    async fn test_update_list_delete() {
+        let store = MemoryStore::new();
+
+        let response = update(&store, vec![member("alice"), member("bob")]).await.unwrap();
+        assert_eq!(response.updates.len(), 2);
+        assert!(response.updates.iter().all(|update| update.replaced.is_none()));
+        let id = response.updates[0].current.id.clone().unwrap();
+
+        let mut renamed = member("alicia");
+        renamed.id = Some(id.clone());
+        let response = update(&store, vec![renamed]).await.unwrap();
+        assert_eq!(response.updates[0].replaced.as_ref().unwrap().name, "alice");
+
+        let listed = list_items::<Member>(&store, ListRequest::default(), "members").await.unwrap();
+        assert_eq!(listed.items.len(), 2);
+        assert!(listed.next_cursor.is_none());
+
+        let response = delete(&store, vec![id, "missing".to_string()]).await.unwrap();
+        assert_eq!(response.deletes[0].removed.as_ref().unwrap().name, "alicia");
+        assert!(response.deletes[1].removed.is_none());
+        assert_eq!(list_items::<Member>(&store, ListRequest::default(), "members").await.unwrap().items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let store = MemoryStore::new();
+        let values = (0..5).map(|i| member(&format!("member{}", i))).collect();
+        update(&store, values).await.unwrap();
+
+        let mut seen = 0;
+        let mut cursor = None;
+        loop {
+            let page = list_items::<Member>(&store, ListRequest { limit: Some(2), cursor, ..Default::default() }, "members").await.unwrap();
+            assert!(page.items.len() <= 2);
+            seen += page.items.len();
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, 5);
+        assert_eq!(list_all_items::<Member>(&store, "members").await.unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_list_filter_sort_fields() {
+        let store = MemoryStore::new();
+        let values = ["dave", "carol", "bob", "alice", "anne"].iter().map(|name| member(name)).collect();
+        update(&store, values).await.unwrap();
+
+        let request = ListRequest {
+            limit: Some(2),
+            filters: vec![Filter { field: "name".to_string(), op: FilterOp::Prefix, value: "a".to_string() }],
+            sort: Some(Sort { field: "name".to_string(), descending: true }),
+            fields: Some(vec!["name".to_string()]),
+            ..Default::default()
+        };
+        let page = list_items::<Member>(&store, request.clone(), "members").await.unwrap();
+        let names: Vec<_> = page.items.iter().map(|item| item.name.clone()).collect();
+        assert_eq!(names, vec!["anne", "alice"]);
+        assert!(page.next_cursor.is_none());
+
+        let json = serde_json::to_value(&page.items[0]).unwrap();
+        let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["id", "name"]);
+
+        let request = ListRequest {
+            limit: Some(3),
+            sort: Some(Sort { field: "name".to_string(), descending: false }),
+            ..Default::default()
+        };
+        let page = list_items::<Member>(&store, request.clone(), "members").await.unwrap();
+        assert_eq!(page.items.iter().map(|item| item.name.clone()).collect::<Vec<_>>(), vec!["alice", "anne", "bob"]);
+        let page = list_items::<Member>(&store, ListRequest { cursor: page.next_cursor, ..request }, "members").await.unwrap();
+        assert_eq!(page.items.iter().map(|item| item.name.clone()).collect::<Vec<_>>(), vec!["carol", "dave"]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_invalid_rows() {
+        let store = MemoryStore::new();
+        update(&store, vec![member("alice")]).await.unwrap();
+        let mut broken = key("broken");
+        broken.insert("name".to_string(), AttributeValue::S("No Email".to_string()));
+        store.put("members", broken, Expect::Anything).await.unwrap();
+
+        let page = list_items::<Member>(&store, ListRequest::default(), "members").await.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.invalid_rows.len(), 1);
+        assert_eq!(page.invalid_rows[0].id.as_deref(), Some("broken"));
+        assert_eq!(page.invalid_rows[0].error, "No value for: email");
+
+        let strict = ListRequest { strict: true, ..Default::default() };
+        assert!(list_items::<Member>(&store, strict, "members").await.is_err());
+        assert_eq!(list_all_items::<Member>(&store, "members").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_versions() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice")]).await.unwrap();
+        let alice = created.updates[0].current.clone();
+        assert_eq!(alice.version, Some(1));
+
+        // Unconditional writes still bump the version
+        let mut overwrite = alice.clone();
+        overwrite.version = None;
+        let response = update(&store, vec![overwrite]).await.unwrap();
+        assert_eq!(response.updates[0].current.version, Some(2));
+
+        // Writing with an old version is a conflict
+        let mut stale = alice.clone();
+        stale.name = "Stale".to_string();
+        let response = update(&store, vec![stale]).await.unwrap();
+        let conflict = response.updates[0].conflict.as_ref().unwrap();
+        assert_eq!(conflict.expected_version, 1);
+        assert_eq!(conflict.server_copy.as_ref().unwrap().version, Some(2));
+        assert_eq!(response.updates[0].current.version, Some(1));
+
+        // Writing with the latest version goes ahead
+        let mut fresh = conflict.server_copy.clone().unwrap();
+        fresh.name = "Fresh".to_string();
+        let response = update(&store, vec![fresh]).await.unwrap();
+        assert!(response.updates[0].conflict.is_none());
+        assert_eq!(response.updates[0].replaced.as_ref().unwrap().version, Some(2));
+        assert_eq!(response.updates[0].current.version, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_all_or_nothing() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice"), member("bob")]).await.unwrap();
+        let alice = created.updates[0].current.clone();
+        let bob = created.updates[1].current.clone();
+
+        // Without all_or_nothing, the good items are still written
+        let mut stale = alice.clone();
+        stale.version = Some(7);
+        let mut renamed = bob.clone();
+        renamed.name = "Robert".to_string();
+        let response = update(&store, vec![stale.clone(), renamed.clone()]).await.unwrap();
+        assert_eq!(response.updates[0].error.as_ref().unwrap().code, CONFLICT);
+        assert!(response.updates[1].error.is_none());
+        assert_eq!(response.updates[1].current.version, Some(2));
+
+        // With all_or_nothing, nothing is written
+        let mut renamed = response.updates[1].current.clone();
+        renamed.name = "Bobby".to_string();
+        let response = update_all(&store, vec![stale, renamed.clone()]).await.unwrap();
+        assert_eq!(response.updates[0].error.as_ref().unwrap().code, CONFLICT);
+        assert_eq!(response.updates[0].conflict.as_ref().unwrap().server_copy.as_ref().unwrap().version, Some(1));
+        assert_eq!(response.updates[1].error.as_ref().unwrap().code, ABORTED);
+        assert_eq!(response.updates[1].current.version, Some(2));
+        let stored = store.get("members", bob.id.as_ref().unwrap()).await.unwrap().unwrap();
+        assert_eq!(stored["name"].as_s().unwrap(), "Robert");
+
+        let response = update_all(&store, vec![alice.clone(), renamed, member("carol")]).await.unwrap();
+        assert!(response.updates.iter().all(|update| update.error.is_none()));
+        assert_eq!(response.updates[1].replaced.as_ref().unwrap().name, "Robert");
+        assert_eq!(response.updates[1].current.version, Some(3));
+        assert_eq!(response.updates[2].current.version, Some(1));
+
+        // The same item can't be changed twice in one transaction
+        let twice = UpdateRequest { values: vec![alice.clone(), alice.clone()], all_or_nothing: true };
+        assert!(update_with(&store, twice, IdStrategy::Timestamped, &RowAccess::All).await.is_err());
+
+        let ids = vec![alice.id.clone().unwrap(), bob.id.clone().unwrap()];
+        let response = delete_all(&store, ids).await.unwrap();
+        assert!(response.deletes.iter().all(|delete| delete.error.is_none() && delete.removed.is_some()));
+        assert_eq!(list_all_items::<Member>(&store, "members").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batched_writes() {
+        let store = SlowStore::default();
+
+        // One at a time, 300 writes would take at least 6 seconds
+        let start = Instant::now();
+        let values = (0..300).map(|i| member(&format!("member{}", i))).collect();
+        let response = update(&store, values).await.unwrap();
+        assert!(response.updates.iter().all(|update| update.error.is_none()));
+        // One read for the emails in use, one for the stored versions, then the batches
+        assert_eq!(store.requests.load(Ordering::SeqCst), 1 + 1 + 12);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Stored items are written one at a time so they can be checked, but still at the same time as each other
+        let mut values: Vec<Member> = response.updates.into_iter().map(|update| update.current).collect();
+        values.truncate(40);
+        for member in values.iter_mut().skip(20) {
+            member.version = None;
+        }
+        values.push(values[25].clone());
+        store.requests.store(0, Ordering::SeqCst);
+        let start = Instant::now();
+        let response = update(&store, values).await.unwrap();
+        assert!(response.updates[..40].iter().all(|update| update.error.is_none() && update.current.version == Some(2)));
+        assert!(response.updates[20].replaced.is_some());
+        assert_eq!(response.updates[40].error.as_ref().unwrap().code, DUPLICATE);
+        assert_eq!(store.requests.load(Ordering::SeqCst), 1 + 1 + 40);
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        let ids: Vec<String> = list_all_items::<Member>(&store.inner, "members").await.unwrap()
+            .into_iter().filter_map(|member| member.id).collect();
+        store.requests.store(0, Ordering::SeqCst);
+        let response = delete(&store, ids).await.unwrap();
+        assert!(response.deletes.iter().all(|delete| delete.error.is_none() && delete.removed.is_some()));
+        // Each is put in the trash only if it hasn't changed since it was read
+        assert_eq!(store.requests.load(Ordering::SeqCst), 1 + 300);
+        assert!(store.inner.list("members").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        let store = MemoryStore::new();
+        let retention = Duration::from_secs(60);
+        let created = update(&store, vec![member("alice"), member("bob")]).await.unwrap();
+        let alice = created.updates[0].current.clone();
+        let bob = created.updates[1].current.clone();
+        let ids = vec![alice.id.clone().unwrap(), bob.id.clone().unwrap()];
+
+        delete(&store, ids.clone()).await.unwrap();
+        assert!(list_all_items::<Member>(&store, "members").await.unwrap().is_empty());
+        let trash = list_trash::<Member>(&store, ListTrashRequest::default(), "members", retention).await.unwrap();
+        assert_eq!(trash.items.len(), 2);
+        assert_eq!(trash.items[0].purge_after, trash.items[0].deleted_at + 60);
+
+        // Deleting again does nothing, and deleted items can't be patched
+        let response = delete(&store, ids.clone()).await.unwrap();
+        assert!(response.deletes.iter().all(|delete| delete.removed.is_none() && delete.error.is_none()));
+        let patch = Patch { id: ids[0].clone(), unset: vec!["mobile".to_string()], ..Default::default() };
+        assert!(patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+
+        // Nor updated, which would take them out of the trash without a restore
+        let mut unversioned = alice.clone();
+        unversioned.version = None;
+        for all_or_nothing in [false, true] {
+            let response = update_with(&store, UpdateRequest { values: vec![unversioned.clone()], all_or_nothing }, IdStrategy::Timestamped, &RowAccess::All).await.unwrap();
+            assert_eq!(response.updates[0].error.as_ref().unwrap().code, NOT_FOUND);
+        }
+        assert!(list_all_items::<Member>(&store, "members").await.unwrap().is_empty());
+
+        let response = restore_items::<Member>(&store, RestoreRequest { ids: vec![ids[0].clone(), "missing".to_string()] }, "members", &audit(&store)).await.unwrap();
+        let restored = response.restores[0].restored.as_ref().unwrap();
+        assert_eq!(restored.name, "alice");
+        assert_eq!(restored.version, Some(3));
+        assert_eq!(response.restores[1].error.as_ref().unwrap().code, NOT_FOUND);
+        assert_eq!(list_all_items::<Member>(&store, "members").await.unwrap().len(), 1);
+
+        // Nothing has been in the trash long enough to purge yet
+        let response = purge_items(&store, PurgeRequest::default(), "members", retention, &audit(&store)).await.unwrap();
+        assert!(response.purged.is_empty());
+        let response = purge_items(&store, PurgeRequest::default(), "members", Duration::ZERO, &audit(&store)).await.unwrap();
+        assert_eq!(response.purged, vec![ids[1].clone()]);
+        assert!(store.get("members", &ids[1]).await.unwrap().is_none());
+
+        // Only trashed items can be purged
+        let response = purge_items(&store, PurgeRequest { ids: vec![ids[0].clone()] }, "members", retention, &audit(&store)).await.unwrap();
+        assert!(response.purged.is_empty());
+        delete_all(&store, vec![ids[0].clone()]).await.unwrap();
+        let response = purge_items(&store, PurgeRequest { ids: vec![ids[0].clone()] }, "members", retention, &audit(&store)).await.unwrap();
+        assert_eq!(response.purged, vec![ids[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_patch() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice")]).await.unwrap();
+        let id = created.updates[0].current.id.clone().unwrap();
+
+        let patch = Patch {
+            id: id.clone(),
+            set: [("mobile".to_string(), PatchValue::Text("+61412345678".to_string()))].into(),
+            add: [("subscriptions".to_string(), vec!["events".to_string()])].into(),
+            remove: [("subscriptions".to_string(), vec!["news".to_string()])].into(),
+            ..Default::default()
+        };
+        // The same field can't be added to and removed from at once
+        assert!(patch_items::<Member>(&store, PatchRequest { patches: vec![patch.clone()] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+
+        let patch = Patch { remove: Default::default(), ..patch };
+        let response = patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.unwrap();
+        let status = &response.updates[0];
+        assert_eq!(status.replaced.as_ref().unwrap().mobile, None);
+        assert_eq!(status.current.mobile, Some(PhoneNumber::new("+61412345678")));
+        assert_eq!(status.current.subscriptions, vec!["news", "events"]);
+        assert_eq!(status.current.version, Some(2));
+
+        // Stale versions conflict
+        let patch = Patch {
+            id: id.clone(),
+            expected_version: Some(1),
+            unset: vec!["mobile".to_string()],
+            ..Default::default()
+        };
+        let response = patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.unwrap();
+        assert_eq!(response.updates[0].conflict.as_ref().unwrap().server_copy.as_ref().unwrap().version, Some(2));
+
+        // Patches that would make the item invalid are refused
+        let patch = Patch { id: id.clone(), unset: vec!["email".to_string()], ..Default::default() };
+        assert!(patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+        let patch = Patch { id, set: [("mobile".to_string(), PatchValue::Text("0412345678".to_string()))].into(), ..Default::default() };
+        let err = patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.unwrap_err();
+        assert_eq!(err.downcast::<ApiError>().unwrap().fields()[0].field, "patches[0].mobile");
+        let missing = Patch { id: "missing".to_string(), unset: vec!["mobile".to_string()], ..Default::default() };
+        assert!(patch_items::<Member>(&store, PatchRequest { patches: vec![missing] }, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validation() {
+        let store = MemoryStore::new();
+        let mut invalid = member("carol");
+        invalid.name = " ".to_string();
+        invalid.email = "carol at example.com".to_string();
+        let values = vec![member("alice"), invalid];
+
+        let err = update(&store, values).await.unwrap_err();
+        let err = err.downcast::<ApiError>().unwrap();
+        assert_eq!(err.code(), "validation");
+        let fields: Vec<_> = err.fields().iter().map(|field| &field.field[..]).collect();
+        assert_eq!(fields, vec!["values[1].name", "values[1].email"]);
+        // Nothing is written when any item is invalid
+        assert!(store.list("members").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_row_access() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice"), member("bob")]).await.unwrap();
+        let alice = created.updates[0].current.clone();
+        let bob_id = created.updates[1].current.id.clone().unwrap();
+        let access = RowAccess::Own { id: alice.id.clone().unwrap(), locked_fields: vec!["role".to_string(), "email".to_string()] };
+        let forbidden = |err: Error| err.downcast::<ApiError>().unwrap().code() == "forbidden";
+
+        let mut renamed = alice.clone();
+        renamed.name = "alicia".to_string();
+        update_with(&store, UpdateRequest { values: vec![renamed.clone()], all_or_nothing: false }, IdStrategy::Timestamped, &access).await.unwrap();
+
+        // Locked fields, other members and new members are off limits
+        renamed.role = Role::Admin;
+        let err = update_with(&store, UpdateRequest { values: vec![renamed], all_or_nothing: false }, IdStrategy::Timestamped, &access).await.unwrap_err();
+        assert!(forbidden(err));
+        let err = update_with(&store, UpdateRequest { values: vec![member("carol")], all_or_nothing: false }, IdStrategy::Timestamped, &access).await.unwrap_err();
+        assert!(forbidden(err));
+        let patch = Patch { id: alice.id.clone().unwrap(), set: [("email".to_string(), PatchValue::Text("new@example.com".to_string()))].into(), ..Default::default() };
+        let err = patch_items::<Member>(&store, PatchRequest { patches: vec![patch] }, "members", &ValidationContext::default(), &access, &audit(&store)).await.unwrap_err();
+        assert!(forbidden(err));
+        let err = delete_with(&store, DeleteRequest { ids: vec![bob_id], all_or_nothing: false }, &access).await.unwrap_err();
+        assert!(forbidden(err));
+        assert_eq!(list_items::<Member>(&store, ListRequest::default(), "members").await.unwrap().items.len(), 2);
+
+        // Updates replace the whole item, so leaving out the role is an error rather than a demotion
+        let without_role = serde_json::json!({ "values": [{ "id": alice.id, "name": "alice", "email": "alice@example.com", "subscriptions": [] }] });
+        assert!(serde_json::from_value::<UpdateRequest<Member>>(without_role).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookups() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice"), member("bob")]).await.unwrap();
+        let alice = created.updates[0].current.clone();
+        let bob = created.updates[1].current.clone();
+        assert_eq!(store.find("members", "email_key", "alice@example.com").await.unwrap().len(), 1);
+
+        // Emails can't be shared by members, whatever their case
+        let mut copy = member("carol");
+        copy.email = "ALICE@example.com".to_string();
+        let err = update(&store, vec![copy]).await.unwrap_err();
+        assert_eq!(err.downcast::<ApiError>().unwrap().fields()[0].field, "values[0].email");
+        let err = update(&store, vec![member("dave"), member("dave")]).await.unwrap_err();
+        assert_eq!(err.downcast::<ApiError>().unwrap().fields().len(), 2);
+        // Keeping your own email is fine
+        let mut renamed = alice.clone();
+        renamed.name = "alicia".to_string();
+        update(&store, vec![renamed]).await.unwrap();
+
+        // Patches keep the lookup in step, and can't take someone else's email
+        let patch = |id: &Option<String>, email: &str| PatchRequest { patches: vec![Patch { id: id.clone().unwrap(), set: [("email".to_string(), PatchValue::Text(email.to_string()))].into(), ..Default::default() }] };
+        patch_items::<Member>(&store, patch(&alice.id, "Alice@New.example.com"), "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.unwrap();
+        assert_eq!(store.find("members", "email_key", "alice@new.example.com").await.unwrap().len(), 1);
+        assert!(store.find("members", "email_key", "alice@example.com").await.unwrap().is_empty());
+        assert!(patch_items::<Member>(&store, patch(&bob.id, "alice@new.example.com"), "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+        let lookup = PatchRequest { patches: vec![Patch { id: bob.id.clone().unwrap(), set: [("email_key".to_string(), PatchValue::Text("x@example.com".to_string()))].into(), ..Default::default() }] };
+        assert!(patch_items::<Member>(&store, lookup, "members", &ValidationContext::default(), &RowAccess::All, &audit(&store)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backfill_lookups() {
+        let store = MemoryStore::new();
+        let created = update(&store, vec![member("alice"), member("bob"), member("carol")]).await.unwrap();
+        let ids: Vec<String> = created.updates.iter().map(|u| u.current.id.clone().unwrap()).collect();
+        // Alice and Bob were written before the lookup existed, and Bob has since been trashed
+        let forget = RowUpdate { remove: vec!["email_key".to_string()], ..Default::default() };
+        for id in &ids[..2] {
+            store.update("members", id, &forget, Expect::Anything).await.unwrap();
+        }
+        delete(&store, vec![ids[1].clone()]).await.unwrap();
+        assert!(store.find("members", "email_key", "alice@example.com").await.unwrap().is_empty());
+
+        assert_eq!(backfill_lookups::<Member>(&store, "members").await.unwrap(), 2);
+        assert_eq!(store.find("members", "email_key", "alice@example.com").await.unwrap().len(), 1);
+        let bob = store.get("members", &ids[1]).await.unwrap().unwrap();
+        assert_eq!(bob.get("email_key").unwrap().as_s().unwrap(), "bob@example.com");
+        // Running it again finds nothing left to do
+        assert_eq!(backfill_lookups::<Member>(&store, "members").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_and_ids() {
+        let store = MemoryStore::new();
+        let created = update_with(&store, UpdateRequest { values: vec![member("alice"), member("bob")], all_or_nothing: false }, IdStrategy::Random, &RowAccess::All).await.unwrap();
+        let alice_id = created.updates[0].current.id.clone().unwrap();
+        let bob_id = created.updates[1].current.id.clone().unwrap();
+        assert_eq!(alice_id.len(), 32);
+
+        // Given ids must be given
+        let err = update_with(&store, UpdateRequest { values: vec![member("carol")], all_or_nothing: false }, IdStrategy::Given, &RowAccess::All).await.unwrap_err();
+        assert_eq!(err.downcast::<ApiError>().unwrap().fields()[0].field, "values[0].id");
+        let mut carol = member("carol");
+        carol.id = Some("carol".to_string());
+        update_with(&store, UpdateRequest { values: vec![carol], all_or_nothing: false }, IdStrategy::Given, &RowAccess::All).await.unwrap();
+
+        delete(&store, vec![bob_id.clone()]).await.unwrap();
+        let request = GetRequest { ids: vec!["carol".to_string(), bob_id.clone(), alice_id.clone(), "missing".to_string()] };
+        let got = get_items::<Member>(&store, request, "members", &RowAccess::All).await.unwrap();
+        let names: Vec<_> = got.items.iter().map(|item| &item.name[..]).collect();
+        assert_eq!(names, vec!["carol", "alice"]);
+        assert_eq!(got.missing, vec![bob_id, "missing".to_string()]);
+
+        let access = RowAccess::Own { id: alice_id.clone(), locked_fields: vec![] };
+        assert!(get_items::<Member>(&store, GetRequest { ids: vec![alice_id] }, "members", &access).await.is_ok());
+        assert!(get_items::<Member>(&store, GetRequest { ids: vec!["carol".to_string()] }, "members", &access).await.is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::marker::PhantomData;
+use app_core::{api::*, validate::Validate};
+use lambda_http::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{audit::{Audit, list_audit}, auth::{Permission, Principal}, config::AppConfig, crud::{IdStrategy, RowAccess, list_items, get_items, update_items, patch_items, delete_items, list_trash, restore_items, purge_items}, router::Router, serialize::{ServerSerialize, Projected}, store::Store};
+
+/**
+ * A kind of item the API stores, e.g. members. Registering it with a router adds its routes:
+ * `/<name>-list`, `-get`, `-update`, `-patch`, `-delete`, `-trash`, `-restore`, `-purge` and `-audit`.
+ * Items are checked with their `Validate` impl before they are written.
+ */
+pub struct Entity<T> {
+    /** Starts the path of each route, e.g. "members" */
+    pub name: &'static str,
+    /** The table the items are stored in */
+    pub table: fn(&AppConfig) -> &str,
+    pub ids: IdStrategy,
+    /** Needed to list and get items */
+    pub read: Permission,
+    /** Needed to create and change items */
+    pub write: Permission,
+    /** Needed to delete items */
+    pub delete: Permission,
+    /** Needed to see the trash and audit log, and to restore and purge items */
+    pub manage: Permission,
+    /** Which items a caller may read and change, once they have the permission */
+    pub access: fn(&Principal) -> RowAccess,
+    item: PhantomData<fn() -> T>,
+}
+
+// Derives would need T to be Copy too
+impl<T> Clone for Entity<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Entity<T> {}
+
+impl<T> Entity<T> {
+    /**
+     * Declares an entity with timestamped ids, that callers with the permissions can read and change every item of
+     */
+    pub fn new(name: &'static str, table: fn(&AppConfig) -> &str, read: Permission, write: Permission) -> Self {
+        Entity {
+            name,
+            table,
+            ids: IdStrategy::Timestamped,
+            read,
+            write,
+            delete: write,
+            manage: write,
+            access: |_| RowAccess::All,
+            item: PhantomData,
+        }
+    }
+
+    pub fn ids(self, ids: IdStrategy) -> Self {
+        Entity { ids, ..self }
+    }
+
+    pub fn delete(self, delete: Permission) -> Self {
+        Entity { delete, ..self }
+    }
+
+    pub fn manage(self, manage: Permission) -> Self {
+        Entity { manage, ..self }
+    }
+
+    pub fn access(self, access: fn(&Principal) -> RowAccess) -> Self {
+        Entity { access, ..self }
+    }
+
+    /**
+     * The path of one of the entity's routes, e.g. "/members-list" for "list"
+     */
+    pub fn path(&self, action: &str) -> String {
+        format!("/{}-{}", self.name, action)
+    }
+}
+
+impl<'a> Router<'a> {
+    /**
+     * Adds every route of the entity
+     */
+    pub fn entity<T>(self, entity: Entity<T>) -> Self
+        where T: ServerSerialize + Validate + Serialize + for<'de> Deserialize<'de> + JsonSchema + Send + Sync + 'static
+    {
+        self.route(entity.path("list"), entity.read, move |config, store, principal, input| list(entity, config, store, principal, input))
+            .route(entity.path("get"), entity.read, move |config, store, principal, input| get(entity, config, store, principal, input))
+            .route(entity.path("update"), entity.write, move |config, store, principal, input| update(entity, config, store, principal, input))
+            .route(entity.path("patch"), entity.write, move |config, store, principal, input| patch(entity, config, store, principal, input))
+            .route(entity.path("delete"), entity.delete, move |config, store, principal, input| delete(entity, config, store, principal, input))
+            .route(entity.path("trash"), entity.manage, move |config, store, principal, input| trash(entity, config, store, principal, input))
+            .route(entity.path("restore"), entity.manage, move |config, store, principal, input| restore(entity, config, store, principal, input))
+            .route(entity.path("purge"), entity.manage, move |config, store, principal, input| purge(entity, config, store, principal, input))
+            .route(entity.path("audit"), entity.manage, move |config, store, principal, input| audit(entity, config, store, principal, input))
+    }
+}
+
+async fn list<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, mut input: ListRequest) -> Result<ListResponse<Projected<T>>, Error> {
+    // Callers limited to their own item only see it
+    if let RowAccess::Own { id, .. } = (entity.access)(&principal) {
+        input.filters.push(Filter { field: "id".to_string(), op: FilterOp::Equals, value: id });
+    }
+    list_items(store, input, (entity.table)(config)).await
+}
+
+async fn get<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: GetRequest) -> Result<GetResponse<T>, Error> {
+    get_items(store, input, (entity.table)(config), &(entity.access)(&principal)).await
+}
+
+async fn update<T: ServerSerialize + Validate>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: UpdateRequest<T>) -> Result<UpdateResponse<T>, Error> {
+    let audit = Audit::new(store, &config.audit_table, principal.actor());
+    update_items(store, input, (entity.table)(config), entity.ids, &config.validation(), &(entity.access)(&principal), &audit).await
+}
+
+async fn patch<T: ServerSerialize + Validate>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: PatchRequest) -> Result<UpdateResponse<T>, Error> {
+    let audit = Audit::new(store, &config.audit_table, principal.actor());
+    patch_items(store, input, (entity.table)(config), &config.validation(), &(entity.access)(&principal), &audit).await
+}
+
+async fn delete<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: DeleteRequest) -> Result<DeleteResponse<T>, Error> {
+    let audit = Audit::new(store, &config.audit_table, principal.actor());
+    delete_items(store, input, (entity.table)(config), &(entity.access)(&principal), &audit).await
+}
+
+async fn trash<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, _principal: Principal, input: ListTrashRequest) -> Result<ListTrashResponse<T>, Error> {
+    list_trash(store, input, (entity.table)(config), config.trash_retention()).await
+}
+
+async fn restore<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: RestoreRequest) -> Result<RestoreResponse<T>, Error> {
+    let audit = Audit::new(store, &config.audit_table, principal.actor());
+    restore_items(store, input, (entity.table)(config), &audit).await
+}
+
+async fn purge<T>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, principal: Principal, input: PurgeRequest) -> Result<PurgeResponse, Error> {
+    let audit = Audit::new(store, &config.audit_table, principal.actor());
+    purge_items(store, input, (entity.table)(config), config.trash_retention(), &audit).await
+}
+
+async fn audit<T: ServerSerialize>(entity: Entity<T>, config: &AppConfig, store: &dyn Store, _principal: Principal, input: ListAuditRequest) -> Result<ListAuditResponse<T>, Error> {
+    let table = (entity.table)(config);
+    list_audit(store, input, &config.audit_table, table).await
+}
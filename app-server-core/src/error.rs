@@ -0,0 +1,184 @@
+use app_core::{api::ItemError, validate::FieldError};
+use aws_sdk_dynamodb::error::{SdkError, ProvideErrorMetadata, DisplayErrorContext};
+use lambda_http::{Error, http::StatusCode, ext::PayloadError};
+
+/** Error codes AWS services use when a request is refused for being sent too often */
+const THROTTLE_CODES: &[&str] = &[
+    "ThrottlingException",
+    "Throttling",
+    "TooManyRequestsException",
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+];
+
+/**
+ * Why a request failed. Each kind has its own HTTP status and a `code` the frontend can check,
+ * and carries a message for people.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    /** The request is malformed or asks for something that isn't allowed */
+    Validation(String),
+    /** Some fields of the request have invalid values. Has the same code as `Validation`. */
+    InvalidFields { message: String, fields: Vec<FieldError> },
+    /** The requested item doesn't exist */
+    NotFound(String),
+    /** The request clashes with a change someone else made */
+    Conflict(String),
+    /** The caller didn't say who they are */
+    Unauthorized(String),
+    /** The caller isn't allowed to do this */
+    Forbidden(String),
+    /** Too many requests were made. Trying again later should work. */
+    RateLimited(String),
+    /** A service we rely on (e.g. DynamoDB) failed */
+    Upstream(String),
+    /** Something went wrong in our own code */
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_) | ApiError::InvalidFields { .. } => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /**
+     * A machine readable name for the kind of error. These never change, so clients can rely on them.
+     */
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) | ApiError::InvalidFields { .. } => "validation",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Upstream(_) => "upstream",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::Validation(message)
+            | ApiError::InvalidFields { message, .. }
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::RateLimited(message)
+            | ApiError::Upstream(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+
+    /**
+     * The fields with invalid values, if that is why the request failed
+     */
+    pub fn fields(&self) -> &[FieldError] {
+        match self {
+            ApiError::InvalidFields { fields, .. } => fields,
+            _ => &[],
+        }
+    }
+
+    /**
+     * Reports every field with an invalid value
+     */
+    pub fn invalid_fields(fields: Vec<FieldError>) -> ApiError {
+        let names: Vec<&str> = fields.iter().map(|field| &field.field[..]).collect();
+        ApiError::InvalidFields {
+            message: format!("Invalid values for: {}", names.join(", ")),
+            fields,
+        }
+    }
+
+    /**
+     * Works out what kind of error a boxed error is.
+     * Errors that aren't an `ApiError` are logged and treated as internal, so their details aren't sent to the caller.
+     */
+    pub fn from_error(err: Error) -> ApiError {
+        let err = match err.downcast::<ApiError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<PayloadError>() {
+            Ok(err) => return ApiError::Validation(format!("Invalid request body: {}", err)),
+            Err(err) => err,
+        };
+        match err.downcast::<serde_json::Error>() {
+            Ok(err) => ApiError::Validation(format!("Invalid JSON: {}", err)),
+            Err(err) => {
+                log::error!("Unexpected error: {}", err);
+                ApiError::Internal("Internal error".to_string())
+            },
+        }
+    }
+
+    /**
+     * Sorts a failed AWS request into throttling, which is worth retrying, or any other upstream failure
+     */
+    pub fn from_sdk<E, R>(err: SdkError<E, R>) -> ApiError
+        where E: ProvideErrorMetadata + std::error::Error + 'static,
+        R: std::fmt::Debug
+    {
+        let detail = DisplayErrorContext(&err).to_string();
+        match err.code() {
+            Some(code) if THROTTLE_CODES.contains(&code) => {
+                log::warn!("AWS request throttled: {}", detail);
+                ApiError::RateLimited("Too many requests, try again later".to_string())
+            },
+            _ => {
+                log::error!("AWS request failed: {}", detail);
+                ApiError::Upstream(format!("A service failed: {}", err.code().unwrap_or("no response")))
+            },
+        }
+    }
+
+    /**
+     * The error for a single item of a bulk request
+     */
+    pub fn to_item_error(&self) -> ItemError {
+        ItemError::new(self.code(), self.message())
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::{Error, http::StatusCode};
+
+    use super::ApiError;
+
+    #[test]
+    fn test_from_error() {
+        let err: Error = ApiError::NotFound("No item with id: 1".to_string()).into();
+        let err = ApiError::from_error(err);
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        assert_eq!(err.code(), "not_found");
+        assert_eq!(err.message(), "No item with id: 1");
+
+        let err: Error = serde_json::from_str::<u32>("{").unwrap_err().into();
+        assert_eq!(ApiError::from_error(err).code(), "validation");
+
+        // Details of unexpected errors stay in the logs
+        let err: Error = "secret connection string".into();
+        assert_eq!(ApiError::from_error(err), ApiError::Internal("Internal error".to_string()));
+    }
+}
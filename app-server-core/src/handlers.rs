@@ -0,0 +1,34 @@
+use app_core::{Member, Topic, api::{ConfirmEmailRequest, ConfirmEmailResponse}};
+
+use crate::{auth::{Authenticator, Permission, Principal}, config::AppConfig, entity::Entity, openapi::Operation, router::Router, store::Store, unsubscribe::{ONE_CLICK_PATH, one_click, unsubscribe}};
+
+/**
+ * Members can see and change their own record. Admins manage everyone's, and are the only ones who can delete
+ * members, so no one can lock themselves out.
+ */
+pub fn members() -> Entity<Member> {
+    Entity::new("members", |config| &config.members_table, Permission::OwnRecord, Permission::OwnRecord)
+        .delete(Permission::ManageMembers)
+        .manage(Permission::ManageMembers)
+        .access(Principal::row_access)
+}
+
+/**
+ * Every member can see the topics. Only admins change them.
+ */
+pub fn topics() -> Entity<Topic> {
+    Entity::new("topics", |config| &config.topics_table, Permission::ReadTopics, Permission::ManageTopics)
+}
+
+/**
+ * The router for every entity's paths, unsubscribing, and the OpenAPI document of the whole API at "/openapi.json"
+ */
+pub fn api<'a>(config: &'a AppConfig, store: &'a dyn Store, auth: &'a Authenticator<'a>) -> Router<'a> {
+    Router::new(config, store, auth)
+        .entity(members())
+        .entity(topics())
+        .public_route("/members-unsubscribe", unsubscribe)
+        .request_route(ONE_CLICK_PATH, one_click)
+        .describe(Operation::new::<ConfirmEmailRequest, ConfirmEmailResponse>("/email-confirm", Some(Permission::ApproveEmails)))
+        .openapi("/openapi.json")
+}
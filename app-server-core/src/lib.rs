@@ -5,42 +5,28 @@ use serde::{Deserialize, Serialize};
 pub mod serialize;
 pub mod runtime;
 pub mod crud;
-
+pub mod store;
+pub mod query;
+pub mod audit;
+pub mod config;
+pub mod error;
+pub mod auth;
+pub mod cors;
+pub mod router;
+pub mod entity;
+pub mod handlers;
+pub mod openapi;
+pub mod unsubscribe;
+pub mod links;
+pub mod broadcast;
+
+pub use error::ApiError;
+
+extern crate self as app_server_core;
 extern crate serde;
 extern crate model;
 extern crate aws_sdk_dynamodb;
 
-#[derive(Debug)]
-pub struct RuntimeError {
-    details: String
-}
-
-impl RuntimeError {
-    pub fn from_str(msg: &str) -> Self {
-        RuntimeError{details: msg.to_string()}
-    }
-
-    pub fn from_string(msg: String) -> Self {
-        RuntimeError { details: msg }
-    }
-
-    pub fn details(self) -> String {
-        self.details
-    } 
-}
-
-impl std::fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f,"{}",self.details)
-    }
-}
-
-impl std::error::Error for RuntimeError {
-    fn description(&self) -> &str {
-        &self.details
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EmailRequest {
     pub topic: Topic,
@@ -0,0 +1,146 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{ApiError, config::AppConfig};
+
+/** How long moderators have to approve an email */
+pub const CONFIRM_LINK_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/** How long unsubscribe links work for. The law wants them to work for at least 30 days after the email is sent. */
+pub const UNSUBSCRIBE_LINK_LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/**
+ * What following a link in an email does
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkAction {
+    /** Sends an email on to a topic's members */
+    ConfirmEmail,
+    /** Takes a topic out of a member's subscriptions */
+    Unsubscribe,
+}
+
+/**
+ * What a link's token allows, and until when
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkClaims {
+    pub action: LinkAction,
+    pub topic_id: String,
+    /** The email to confirm, or the member to unsubscribe */
+    pub subject_id: String,
+    /** Seconds since the unix epoch that the link stops working at */
+    pub expires: u64,
+}
+
+/**
+ * Signs the tokens in links from emails, so only the people the emails were sent to can use them.
+ * Tokens are `<claims>.<signature>` in base64url, with an HMAC-SHA256 signature.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct LinkSigner<'a> {
+    keys: &'a [String],
+}
+
+impl<'a> LinkSigner<'a> {
+    /**
+     * Signs with the first key, and accepts tokens signed with any of them so keys can be rotated
+     */
+    pub fn new(keys: &'a [String]) -> Self {
+        LinkSigner { keys }
+    }
+
+    pub fn from_config(config: &'a AppConfig) -> Self {
+        LinkSigner::new(&config.link_keys)
+    }
+
+    /**
+     * A token for a link that lets whoever has it do the action until the lifetime is up
+     */
+    pub fn sign(&self, action: LinkAction, topic_id: &str, subject_id: &str, lifetime: Duration) -> Result<String, ApiError> {
+        let claims = LinkClaims {
+            action,
+            topic_id: topic_id.to_string(),
+            subject_id: subject_id.to_string(),
+            expires: now() + lifetime.as_secs(),
+        };
+        let key = self.keys.first().ok_or_else(|| ApiError::Internal("No key to sign links with".to_string()))?;
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).map_err(|err| ApiError::Internal(err.to_string()))?);
+        let signature = URL_SAFE_NO_PAD.encode(mac(key, &payload).finalize().into_bytes());
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /**
+     * Checks the token was signed by one of the keys, is for the action on the topic and subject, and hasn't expired
+     */
+    pub fn verify(&self, token: &str, action: LinkAction, topic_id: &str, subject_id: &str) -> Result<LinkClaims, ApiError> {
+        let invalid = || ApiError::Forbidden("The link is invalid. Use the link from the email as it was sent.".to_string());
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        if !self.keys.iter().any(|key| mac(key, payload).verify_slice(&signature).is_ok()) {
+            return Err(invalid());
+        }
+        let claims: LinkClaims = URL_SAFE_NO_PAD.decode(payload).ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(invalid)?;
+        if claims.action != action || claims.topic_id != topic_id || claims.subject_id != subject_id {
+            return Err(invalid());
+        }
+        if claims.expires <= now() {
+            return Err(ApiError::Forbidden("The link has expired".to_string()));
+        }
+        Ok(claims)
+    }
+}
+
+fn mac(key: &str, payload: &str) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::ApiError;
+    use super::{LinkAction, LinkSigner};
+
+    #[test]
+    fn test_links() {
+        let old_keys = vec!["old-link-secret-with-at-least-32-bytes".to_string()];
+        let keys = vec!["new-link-secret-with-at-least-32-bytes".to_string(), old_keys[0].clone()];
+        let signer = LinkSigner::new(&keys);
+        let lifetime = Duration::from_secs(60);
+
+        let token = signer.sign(LinkAction::Unsubscribe, "t1", "m1", lifetime).unwrap();
+        let claims = signer.verify(&token, LinkAction::Unsubscribe, "t1", "m1").unwrap();
+        assert_eq!(claims.subject_id, "m1");
+        assert!(signer.verify(&token, LinkAction::ConfirmEmail, "t1", "m1").is_err());
+        assert!(signer.verify(&token, LinkAction::Unsubscribe, "t1", "m2").is_err());
+        assert!(signer.verify(&token, LinkAction::Unsubscribe, "t2", "m1").is_err());
+        assert!(signer.verify("not a token", LinkAction::Unsubscribe, "t1", "m1").is_err());
+
+        // Tokens signed before a key is rotated in still work, until the old key is dropped
+        let old_token = LinkSigner::new(&old_keys).sign(LinkAction::ConfirmEmail, "t1", "email-1", lifetime).unwrap();
+        assert!(signer.verify(&old_token, LinkAction::ConfirmEmail, "t1", "email-1").is_ok());
+        assert!(LinkSigner::new(&keys[..1]).verify(&old_token, LinkAction::ConfirmEmail, "t1", "email-1").is_err());
+
+        // Changing the claims breaks the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", old_token.split_once('.').unwrap().0, signature);
+        assert!(signer.verify(&forged, LinkAction::ConfirmEmail, "t1", "email-1").is_err());
+
+        let expired = signer.sign(LinkAction::Unsubscribe, "t1", "m1", Duration::ZERO).unwrap();
+        let err = signer.verify(&expired, LinkAction::Unsubscribe, "t1", "m1").unwrap_err();
+        assert_eq!(err, ApiError::Forbidden("The link has expired".to_string()));
+    }
+}
@@ -0,0 +1,156 @@
+use app_core::api::ErrorResponse;
+use schemars::{JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings, transform::RecursiveTransform};
+use serde_json::{json, Map, Value};
+
+use crate::auth::Permission;
+
+/**
+ * One path of the API, as the OpenAPI document describes it
+ */
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub path: String,
+    /** Needed to call the path. Public paths need none. */
+    pub permission: Option<Permission>,
+    request: fn(&mut SchemaGenerator) -> Schema,
+    response: fn(&mut SchemaGenerator) -> Schema,
+}
+
+impl Operation {
+    /**
+     * Describes a path that is sent a `T` and answers with an `R`
+     */
+    pub fn new<T: JsonSchema, R: JsonSchema>(path: impl Into<String>, permission: Option<Permission>) -> Self {
+        Operation {
+            path: path.into(),
+            permission,
+            request: SchemaGenerator::subschema_for::<T>,
+            response: SchemaGenerator::subschema_for::<R>,
+        }
+    }
+}
+
+/**
+ * An OpenAPI 3.1 document of the operations.
+ * The types they send and receive are in `components/schemas`, so each is only described once.
+ */
+pub fn document(operations: &[Operation]) -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+            settings.transforms.push(Box::new(RecursiveTransform(tidy_description)));
+        })
+        .into_generator();
+    let error = generator.subschema_for::<ErrorResponse>();
+
+    let mut paths = Map::new();
+    for operation in operations {
+        let mut post = json!({
+            "operationId": operation_id(&operation.path),
+            "requestBody": {
+                "required": true,
+                "content": { "application/json": { "schema": (operation.request)(&mut generator) } },
+            },
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": { "application/json": { "schema": (operation.response)(&mut generator) } },
+                },
+                "default": {
+                    "description": "Why the request failed",
+                    "content": { "application/json": { "schema": error } },
+                },
+            },
+        });
+        match operation.permission {
+            Some(permission) => {
+                let roles: Vec<String> = permission.roles().iter().filter_map(|role| serde_json::to_value(role).ok()?.as_str().map(String::from)).collect();
+                post["description"] = json!(format!("Needs the {:?} permission, which these roles have: {}", permission, roles.join(", ")));
+            },
+            None => post["security"] = json!([]),
+        }
+        paths.insert(operation.path.clone(), json!({ "post": post }));
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": "sin(ln) API", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+        },
+        "security": [{ "bearer": [] }],
+    })
+}
+
+/**
+ * Drops the stars that start each line of the `/** */` doc comments the descriptions come from
+ */
+fn tidy_description(schema: &mut Schema) {
+    if let Some(Value::String(description)) = schema.get_mut("description") {
+        *description = description.lines()
+            .map(|line| line.trim().trim_start_matches('*').trim())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+/**
+ * The name code generators give the operation's function, e.g. "membersList" for "/members-list"
+ */
+fn operation_id(path: &str) -> String {
+    let mut words = path.trim_matches('/').split(['-', '/']);
+    let mut id = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            id.extend(first.to_uppercase());
+            id.push_str(chars.as_str());
+        }
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::{Body, Request, http::StatusCode};
+    use serde_json::Value;
+
+    use crate::{auth::Authenticator, config::AppConfig, handlers::api, store::MemoryStore};
+    use super::operation_id;
+
+    /** Where the checked in document is. Run the tests with UPDATE_OPENAPI=1 to rewrite it after changing the API. */
+    const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    #[test]
+    fn test_operation_id() {
+        assert_eq!(operation_id("/members-list"), "membersList");
+        assert_eq!(operation_id("/email-confirm/"), "emailConfirm");
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let config = AppConfig::for_tests();
+        let store = MemoryStore::new();
+        let auth = Authenticator::from_config(&config, &store).unwrap();
+        let router = api(&config, &store, &auth);
+        let document = router.document();
+        let text = serde_json::to_string_pretty(&document).unwrap() + "\n";
+        if std::env::var("UPDATE_OPENAPI").is_ok() {
+            std::fs::write(SNAPSHOT, &text).unwrap();
+        }
+        let snapshot = std::fs::read_to_string(SNAPSHOT).unwrap();
+        assert!(text == snapshot, "The API changed. Check the differences and update {} by running the tests with UPDATE_OPENAPI=1", SNAPSHOT);
+
+        // Served without signing in
+        let mut request = Request::new(Body::Empty);
+        *request.uri_mut() = "https://api.sinln.mdsimmo.com/openapi.json".parse().unwrap();
+        let response = router.handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(serde_json::from_str::<Value>(response.body()).unwrap(), document);
+    }
+}
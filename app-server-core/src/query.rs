@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use app_core::api::{Filter, FilterOp, Sort};
+use aws_sdk_dynamodb::types::AttributeValue;
+
+use crate::store::Row;
+
+/**
+ * Checks if a row passes every filter
+ */
+pub fn matches(row: &Row, filters: &[Filter]) -> bool {
+    filters.iter().all(|filter| matches_filter(row, filter))
+}
+
+fn matches_filter(row: &Row, filter: &Filter) -> bool {
+    let attribute = match row.get(&filter.field) {
+        Some(attribute) => attribute,
+        None => return false,
+    };
+    match (filter.op, attribute) {
+        (FilterOp::Equals, AttributeValue::S(s)) => s == &filter.value,
+        (FilterOp::Equals, AttributeValue::N(n)) => match (n.parse::<f64>(), filter.value.parse::<f64>()) {
+            (Ok(n), Ok(value)) => n == value,
+            _ => n == &filter.value,
+        },
+        (FilterOp::Equals, AttributeValue::Bool(b)) => filter.value.parse::<bool>() == Ok(*b),
+        (FilterOp::Prefix, AttributeValue::S(s)) => s.starts_with(&filter.value),
+        (FilterOp::Contains, AttributeValue::S(s)) => s.contains(&filter.value),
+        (FilterOp::Contains, AttributeValue::Ss(list)) => list.contains(&filter.value),
+        (FilterOp::Contains, AttributeValue::L(list)) => list.iter()
+            .any(|item| item.as_s() == Ok(&filter.value)),
+        _ => false,
+    }
+}
+
+/**
+ * Orders rows by the sort field, then by id so the order is always the same.
+ * Rows missing the field come first.
+ */
+pub fn sort_rows(rows: &mut [Row], sort: &Sort) {
+    rows.sort_by(|a, b| {
+        let order = compare(a.get(&sort.field), b.get(&sort.field))
+            .then_with(|| compare(a.get("id"), b.get("id")));
+        if sort.descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+}
+
+fn compare(a: Option<&AttributeValue>, b: Option<&AttributeValue>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(AttributeValue::N(a)), Some(AttributeValue::N(b))) => match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            _ => a.cmp(b),
+        },
+        (Some(AttributeValue::Bool(a)), Some(AttributeValue::Bool(b))) => a.cmp(b),
+        (Some(a), Some(b)) => sort_text(a).cmp(&sort_text(b)),
+    }
+}
+
+fn sort_text(attribute: &AttributeValue) -> String {
+    match attribute {
+        AttributeValue::S(s) | AttributeValue::N(s) => s.to_lowercase(),
+        AttributeValue::Bool(b) => b.to_string(),
+        AttributeValue::Ss(list) => list.join(",").to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/**
+ * Removes every attribute from the row that is not in the fields
+ */
+pub fn project(row: &mut Row, fields: &[String]) {
+    row.retain(|key, _| fields.contains(key));
+}
+
+#[cfg(test)]
+mod tests {
+    use app_core::api::{Filter, FilterOp, Sort};
+    use aws_sdk_dynamodb::types::AttributeValue;
+
+    use crate::store::{Row, key};
+    use super::{matches, sort_rows};
+
+    fn row(id: &str, name: &str, age: u32, subscriptions: &[&str]) -> Row {
+        let mut row = key(id);
+        row.insert("name".to_string(), AttributeValue::S(name.to_string()));
+        row.insert("age".to_string(), AttributeValue::N(age.to_string()));
+        row.insert("subscriptions".to_string(), AttributeValue::Ss(subscriptions.iter().map(|s| s.to_string()).collect()));
+        row
+    }
+
+    fn filter(field: &str, op: FilterOp, value: &str) -> Filter {
+        Filter { field: field.to_string(), op, value: value.to_string() }
+    }
+
+    #[test]
+    fn test_matches() {
+        let row = row("1", "Alice Smith", 30, &["news", "events"]);
+        assert!(matches(&row, &[]));
+        assert!(matches(&row, &[filter("name", FilterOp::Equals, "Alice Smith")]));
+        assert!(matches(&row, &[filter("name", FilterOp::Prefix, "Alice")]));
+        assert!(matches(&row, &[filter("name", FilterOp::Contains, "Smith"), filter("age", FilterOp::Equals, "30")]));
+        assert!(matches(&row, &[filter("subscriptions", FilterOp::Contains, "events")]));
+        assert!(!matches(&row, &[filter("subscriptions", FilterOp::Contains, "even")]));
+        assert!(!matches(&row, &[filter("name", FilterOp::Prefix, "Smith")]));
+        assert!(!matches(&row, &[filter("missing", FilterOp::Equals, "")]));
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut rows = vec![row("1", "bob", 9, &[]), row("2", "Alice", 10, &[]), row("3", "carol", 100, &[])];
+
+        sort_rows(&mut rows, &Sort { field: "name".to_string(), descending: false });
+        let ids: Vec<_> = rows.iter().map(|row| row["id"].as_s().unwrap().clone()).collect();
+        assert_eq!(ids, vec!["2", "1", "3"]);
+
+        sort_rows(&mut rows, &Sort { field: "age".to_string(), descending: true });
+        let ids: Vec<_> = rows.iter().map(|row| row["id"].as_s().unwrap().clone()).collect();
+        assert_eq!(ids, vec!["3", "2", "1"]);
+    }
+}
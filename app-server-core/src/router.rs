@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use lambda_http::{Error, Request, Response, http::StatusCode, service_fn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{ApiError, auth::{Authenticator, Permission, Principal}, config::AppConfig, cors::Cors, openapi::{Operation, document}, runtime::{StringResponse, run_handler, run_public_handler, run_request_handler, error_response}, store::Store};
+
+/** Runs one route's handler on a request */
+type Route<'a> = Box<dyn Fn(Request) -> BoxFuture<'a, Result<StringResponse, Error>> + Send + Sync + 'a>;
+
+/**
+ * Sends requests to the handler for their path, e.g. "/members-list".
+ * The same router serves every deployment: one lambda per path, one lambda for all of them, or a local server.
+ */
+pub struct Router<'a> {
+    config: &'a AppConfig,
+    store: &'a dyn Store,
+    auth: &'a Authenticator<'a>,
+    routes: BTreeMap<String, Route<'a>>,
+    /** What each route is sent and answers with, for the OpenAPI document */
+    operations: Vec<Operation>,
+}
+
+impl<'a> Router<'a> {
+    /**
+     * Creates a router without any routes. Handlers are given the config and store.
+     */
+    pub fn new(config: &'a AppConfig, store: &'a dyn Store, auth: &'a Authenticator<'a>) -> Self {
+        Router { config, store, auth, routes: BTreeMap::new(), operations: vec![] }
+    }
+
+    /**
+     * Handles requests to the path with the handler, for callers with the permission
+     */
+    pub fn route<T, R, Fut, F>(mut self, path: impl Into<String>, permission: Permission, handler: F) -> Self
+        where F: Fn(&'a AppConfig, &'a dyn Store, Principal, T) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Result<R, Error>> + Send + 'a,
+        T: for<'de> Deserialize<'de> + JsonSchema + Send + 'a,
+        R: Serialize + JsonSchema + 'a
+    {
+        let path = path.into();
+        self.operations.push(Operation::new::<T, R>(&path, Some(permission)));
+        let (config, store, auth) = (self.config, self.store, self.auth);
+        let handler = Arc::new(handler);
+        self.routes.insert(path, Box::new(move |event| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let cors = Cors::from_config(config);
+                run_handler(&cors, auth, permission, &|principal, input| handler(config, store, principal, input), event).await
+            })
+        }));
+        self
+    }
+
+    /**
+     * Handles requests to the path with the handler, for anyone, e.g. people following a link in an email
+     */
+    pub fn public_route<T, R, Fut, F>(mut self, path: impl Into<String>, handler: F) -> Self
+        where F: Fn(&'a AppConfig, &'a dyn Store, T) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Result<R, Error>> + Send + 'a,
+        T: for<'de> Deserialize<'de> + JsonSchema + Send + 'a,
+        R: Serialize + JsonSchema + 'a
+    {
+        let path = path.into();
+        self.operations.push(Operation::new::<T, R>(&path, None));
+        let (config, store) = (self.config, self.store);
+        let handler = Arc::new(handler);
+        self.routes.insert(path, Box::new(move |event| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let cors = Cors::from_config(config);
+                run_public_handler(&cors, &|input| handler(config, store, input), event).await
+            })
+        }));
+        self
+    }
+
+    /**
+     * Handles requests to the path for anyone, with a handler that reads the request itself.
+     * It isn't in the OpenAPI document, since it isn't sent JSON.
+     */
+    pub fn request_route<R, Fut, F>(mut self, path: impl Into<String>, handler: F) -> Self
+        where F: Fn(&'a AppConfig, &'a dyn Store, Request) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Result<R, Error>> + Send + 'a,
+        R: Serialize + 'a
+    {
+        let (config, store) = (self.config, self.store);
+        let handler = Arc::new(handler);
+        self.routes.insert(path.into(), Box::new(move |event| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let cors = Cors::from_config(config);
+                run_request_handler(&cors, &|event| handler(config, store, event), event).await
+            })
+        }));
+        self
+    }
+
+    /**
+     * Documents a path that another lambda serves, so the OpenAPI document covers the whole API
+     */
+    pub fn describe(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /**
+     * The OpenAPI document of the routes and described paths
+     */
+    pub fn document(&self) -> Value {
+        document(&self.operations)
+    }
+
+    /**
+     * Serves the OpenAPI document at the path to anyone, with any method.
+     * Add it last, since it only documents what was added before it.
+     */
+    pub fn openapi(mut self, path: &str) -> Self {
+        let body = self.document().to_string();
+        let config = self.config;
+        self.routes.insert(path.to_string(), Box::new(move |event| {
+            let body = body.clone();
+            Box::pin(async move {
+                let cors = Cors::from_config(config);
+                if Cors::is_preflight(&event) {
+                    return cors.preflight(&event);
+                }
+                let response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .body(body)
+                    .map_err(Box::new)?;
+                Ok(cors.add_headers(cors.allowed_origin(&event), response))
+            })
+        }));
+        self
+    }
+
+    /**
+     * Drops every route but the path, for a lambda that only serves that path
+     */
+    pub fn only(mut self, path: &str) -> Self {
+        self.routes.retain(|route, _| route == path);
+        self
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        self.routes.keys().map(|path| &path[..]).collect()
+    }
+
+    /**
+     * Runs the handler for the request's path. Unknown paths get a not found error.
+     */
+    pub async fn handle(&self, event: Request) -> Result<StringResponse, Error> {
+        let path = event.uri().path().trim_end_matches('/');
+        match self.routes.get(path) {
+            Some(route) => route(event).await,
+            None => {
+                let cors = Cors::from_config(self.config);
+                let error = ApiError::NotFound(format!("No API at: {}", event.uri().path()));
+                Ok(cors.add_headers(cors.allowed_origin(&event), error_response(&error)?))
+            },
+        }
+    }
+
+    /**
+     * Serves requests from API Gateway until the lambda is shut down
+     */
+    pub async fn run_lambda(&self) -> Result<(), Error> {
+        lambda_http::run(service_fn(|event| self.handle(event))).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode, get_current_timestamp};
+    use lambda_http::{Body, Request, http::{Method, StatusCode}};
+    use serde_json::{json, Value};
+
+    use crate::{ListRequest, ListResponse, auth::{Authenticator, Permission, Principal}, config::AppConfig, store::{Expect, MemoryStore, Row, Store}};
+    use super::Router;
+
+    fn request(path: &str, method: Method) -> Request {
+        let claims = json!({
+            "sub": "user-1",
+            "email": "admin@example.com",
+            "email_verified": true,
+            "iss": "https://auth.example.com/",
+            "aud": "sinln",
+            "exp": get_current_timestamp() + 600,
+        });
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"test-secret-with-at-least-32-bytes")).unwrap();
+        let mut request = Request::new(Body::Text("{}".to_string()));
+        *request.method_mut() = method;
+        *request.uri_mut() = format!("https://api.sinln.mdsimmo.com{}", path).parse().unwrap();
+        request.headers_mut().insert("Content-Type", "application/json".parse().unwrap());
+        request.headers_mut().insert("Origin", "https://sinln.mdsimmo.com".parse().unwrap());
+        request.headers_mut().insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+        request
+    }
+
+    async fn count(config: &AppConfig, store: &dyn Store, _principal: Principal, _input: ListRequest) -> Result<ListResponse<String>, lambda_http::Error> {
+        let items = store.list(&config.members_table).await?.iter().map(|row| format!("{:?}", row["id"])).collect();
+        Ok(ListResponse { items, next_cursor: None, invalid_rows: vec![] })
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let config = AppConfig::for_tests();
+        let store = MemoryStore::new();
+        let mut admin = Row::new();
+        admin.insert("id".to_string(), AttributeValue::S("1".to_string()));
+        admin.insert("email".to_string(), AttributeValue::S("admin@example.com".to_string()));
+        admin.insert("email_key".to_string(), AttributeValue::S("admin@example.com".to_string()));
+        admin.insert("role".to_string(), AttributeValue::S("admin".to_string()));
+        store.put("members", admin, Expect::Anything).await.unwrap();
+        let auth = Authenticator::from_config(&config, &store).unwrap();
+        let router = Router::new(&config, &store, &auth)
+            .route("/members-count", Permission::ManageMembers, count)
+            .route("/topics-count", Permission::ManageTopics, count);
+        assert_eq!(router.paths(), vec!["/members-count", "/topics-count"]);
+
+        let response = router.handle(request("/members-count/", Method::POST)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://sinln.mdsimmo.com");
+
+        assert_eq!(router.handle(request("/members-count", Method::OPTIONS)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = router.handle(request("/unknown", Method::POST)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://sinln.mdsimmo.com");
+
+        let router = router.only("/topics-count");
+        assert_eq!(router.handle(request("/members-count", Method::POST)).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(router.handle(request("/topics-count", Method::POST)).await.unwrap().status(), StatusCode::OK);
+    }
+}
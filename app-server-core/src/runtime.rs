@@ -7,19 +7,47 @@ extern crate model;
 extern crate aws_sdk_dynamodb;
 use std::future::Future;
 use lambda_http::{http::StatusCode, Error, Response, Request, RequestExt};
-use serde_json::json;
 
-use crate::RuntimeError;
+use crate::{ApiError, auth::{Authenticator, Permission, Principal}, cors::Cors};
 
 pub type StringResponse = lambda_http::Response<std::string::String>;
 
-pub async fn run_handler<T, R, Fut>(f: &impl Fn(T) -> Fut, event: Request)
+/**
+ * Runs a handler for signed in callers with the permission.
+ * Their bearer token and role are checked before anything else is read from the request.
+ * Preflight requests are answered without running the handler, since browsers send them without the token.
+ */
+pub async fn run_handler<T, R, Fut>(cors: &Cors<'_>, auth: &Authenticator<'_>, permission: Permission, f: &impl Fn(Principal, T) -> Fut, event: Request)
+    -> Result<StringResponse, Error>
+    where Fut: Future<Output = Result<R, Error>> + Send,
+    T: for<'de> Deserialize<'de>,
+    R: Serialize
+{
+    if Cors::is_preflight(&event) {
+        return cors.preflight(&event);
+    }
+    let origin = cors.allowed_origin(&event);
+    let result = match auth.authorize(&event, permission).await {
+        Ok(principal) => run_handler_event(&|input| f(principal.clone(), input), event).await,
+        Err(err) => Err(err.into()),
+    };
+    wrap_errors(result).map(|response| cors.add_headers(origin, response))
+}
+
+/**
+ * Runs a handler that anyone can call, e.g. one opened from a link in an email
+ */
+pub async fn run_public_handler<T, R, Fut>(cors: &Cors<'_>, f: &impl Fn(T) -> Fut, event: Request)
     -> Result<StringResponse, Error>
     where Fut: Future<Output = Result<R, Error>> + Send,
     T: for<'de> Deserialize<'de>,
     R: Serialize
 {
-    add_cors(wrap_errors(run_handler_event(f, event).await))
+    if Cors::is_preflight(&event) {
+        return cors.preflight(&event);
+    }
+    let origin = cors.allowed_origin(&event);
+    wrap_errors(run_handler_event(f, event).await).map(|response| cors.add_headers(origin, response))
 }
 
 /**
@@ -32,66 +60,138 @@ async fn run_handler_event<T, R, Fut>(f: &impl Fn(T) -> Fut, event: Request)
     R: Serialize
 {
     // Decrypt the data
-    let payload = event.payload::<T>()?;
+    let payload = event.payload::<T>()
+        .map_err(|err| ApiError::Validation(format!("Invalid request body: {}", err)))?;
     let data = match payload {
         Some(x) => x,
-        None => return Err(Box::new(RuntimeError::from_str("No data given"))),
+        None => return Err(Box::new(ApiError::Validation("No data given".to_string()))),
     };
 
     // Run the function
     let result = f(data).await?;
+    json_response(&result)
+} 
+
+/**
+ * Runs a handler that anyone can call, and that reads the request itself, e.g. one posted a form rather than JSON
+ */
+pub async fn run_request_handler<R, Fut>(cors: &Cors<'_>, f: &impl Fn(Request) -> Fut, event: Request)
+    -> Result<StringResponse, Error>
+    where Fut: Future<Output = Result<R, Error>> + Send,
+    R: Serialize
+{
+    if Cors::is_preflight(&event) {
+        return cors.preflight(&event);
+    }
+    let origin = cors.allowed_origin(&event);
+    let result = match f(event).await {
+        Ok(result) => json_response(&result),
+        Err(err) => Err(err),
+    };
+    wrap_errors(result).map(|response| cors.add_headers(origin, response))
+}
 
-    // Construct response
-    let response_body = serde_json::to_string(&result)?;
+fn json_response<R: Serialize>(result: &R) -> Result<StringResponse, Error> {
     let response = Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(response_body)
+        .body(serde_json::to_string(result)?)
         .map_err(Box::new)?;
-    
     Ok(response)
-} 
+}
 
 /**
- * Wraps all errors as a 400 response.
+ * Turns errors into a response with the error's status and `{"code", "error"}` body, plus `"fields"` for invalid fields.
  * If they don't get wrapped, AWS Gateway will send a 500 response and not display the error message
  */
 fn wrap_errors(result: Result<StringResponse, Error>) -> Result<StringResponse, Error> {
     match result {
         Ok(r) => Ok(r),
-        Err(e) => {
-            let new_response = Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("Content-Type", "application/json")
-            .body(json!({
-                "error": e.to_string(),
-                "source": match e.source() {
-                    Some(cause) => cause.to_string(),
-                    None => "none".to_string(),
-                }
-              }).to_string())
-            .map_err(Box::new)?;
-            Ok(new_response)
-        }
+        Err(e) => error_response(&ApiError::from_error(e)),
     }
 }
 
-fn add_cors(response: Result<StringResponse, Error>) -> Result<StringResponse, Error> {
-    match response {
-        Ok(msg) => {
-            let mut builder = Response::builder();
-            {
-                let headers = builder.headers_mut().unwrap();
-                headers.clone_from(msg.headers());
-                //headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("http://localhost:3000"));
-            }
-            let r = builder
-                .status(msg.status())
-                .version(msg.version())
-                .header("access-control-allow-origin", "http://localhost:3000")
-                .body(msg.into_body()).map_err(Box::new)?;
-            Ok(r)
+pub fn error_response(error: &ApiError) -> Result<StringResponse, Error> {
+    let response = Response::builder()
+        .status(error.status())
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&ErrorResponse {
+            code: error.code().to_string(),
+            error: error.message().to_string(),
+            fields: error.fields().to_vec(),
+        })?)
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::{Body, Error, Request, http::{Method, StatusCode}};
+    use serde_json::Value;
+
+    use crate::{ApiError, auth::{Authenticator, Permission, Principal}, cors::Cors, store::MemoryStore};
+    use super::{run_handler, run_public_handler};
+
+    const ORIGIN: &str = "https://sinln.mdsimmo.com";
+
+    fn request(body: &str) -> Request {
+        let mut request = Request::new(Body::Text(body.to_string()));
+        request.headers_mut().insert("Content-Type", "application/json".parse().unwrap());
+        request.headers_mut().insert("Origin", ORIGIN.parse().unwrap());
+        request
+    }
+
+    async fn find(id: String) -> Result<String, Error> {
+        match id.as_str() {
+            "1" => Ok("found".to_string()),
+            _ => Err(ApiError::NotFound(format!("No item with id: {}", id)).into()),
         }
-        Err(e) => Err(e)
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let origins = vec![ORIGIN.to_string()];
+        let cors = Cors::new(&origins);
+        let response = run_public_handler(&cors, &find, request("\"1\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = run_public_handler(&cors, &find, request("\"2\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["error"], "No item with id: 2");
+        // Browsers can only read the error if it has CORS headers too
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], ORIGIN);
+
+        let response = run_public_handler(&cors, &find, request("{")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["code"], "validation");
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let origins = vec![ORIGIN.to_string()];
+        let cors = Cors::new(&origins);
+        let key_set = r#"{"keys": [{"kty": "oct", "k": "c2VjcmV0LXNlY3JldC1zZWNyZXQtc2VjcmV0LXNlY3JldA"}]}"#;
+        let store = MemoryStore::new();
+        let auth = Authenticator::new("https://auth.example.com/", "sinln", key_set, &store, "members").unwrap();
+        let handler = |_: Principal, id: String| find(id);
+
+        let response = run_handler(&cors, &auth, Permission::ReadTopics, &handler, request("\"1\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["code"], "unauthorized");
+
+        let mut forged = request("\"1\"");
+        forged.headers_mut().insert("Authorization", "Bearer not.a.token".parse().unwrap());
+        assert_eq!(run_handler(&cors, &auth, Permission::ReadTopics, &handler, forged).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        // Browsers check they may send the token before sending it
+        let mut preflight = request("");
+        *preflight.method_mut() = Method::OPTIONS;
+        let response = run_handler(&cors, &auth, Permission::ReadTopics, &handler, preflight).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], ORIGIN);
     }
 }
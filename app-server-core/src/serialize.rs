@@ -4,165 +4,425 @@ pub use app_core::api::*;
 extern crate serde;
 extern crate model;
 extern crate aws_sdk_dynamodb;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Arc;
 use aws_sdk_dynamodb::types::AttributeValue;
+use schemars::{JsonSchema, Schema, SchemaGenerator};
+use serde::{Serialize, Serializer, ser::Error};
+use serde_json::Value;
 
-use crate::RuntimeError;
+use crate::ApiError;
 
 pub trait ServerSerialize:Sized  {
-    fn from_row(data: &HashMap<String, AttributeValue>) -> Result<Self, RuntimeError>;
+    /** The attributes that must be read for `from_row` to succeed */
+    const REQUIRED_FIELDS: &'static [&'static str];
+
+    /** Each field stored lowercased in a lookup attribute, as `(field, lookup)`. See `Store::find`. */
+    const LOOKUPS: &'static [(&'static str, &'static str)] = &[];
+
+    fn from_row(data: &HashMap<String, AttributeValue>) -> Result<Self, ApiError>;
+    #[allow(clippy::wrong_self_convention)]
     fn into_row(&self) -> HashMap<String, AttributeValue>;
 
     fn id(&self) -> Option<&str>;
     fn set_id(&mut self, id: String) -> &mut Self;
+
+    /** The version of the row the item was read from, if known */
+    fn version(&self) -> Option<u64>;
+    fn set_version(&mut self, version: u64) -> &mut Self;
 }
 
-impl ServerSerialize for Member {
-
-    fn from_row(data: &HashMap<String, AttributeValue>) -> Result<Self, RuntimeError> {
-        let id = read_string(data, "id")?.to_string();
-        let name = read_string(data, "name")?.to_string();
-        let email = read_string(data, "email")?.to_string();
-        let address = read_string_optional(data, "address").map(|x| x.to_string());
-        let mobile = read_integer_optional(data, "mobile");
-        let subscriptions = read_string_list(data, "subscriptions").map_or_else(
-            || vec![],
-            |vec| vec.to_owned()
-        );
-        Ok(Member {
-            id: Some(id),
-            name,
-            email,
-            address,
-            mobile,
-            subscriptions,
-        })
-    }
-
-    fn into_row(&self) -> HashMap<String, AttributeValue> {
-        let mut map = HashMap::new();
-        if let Some(id) = &self.id {
-            map.insert("id".to_string(), AttributeValue::S(id.clone()));
-        }
-        map.insert("name".to_string(), AttributeValue::S(self.name.clone()));
-        map.insert("email".to_string(), AttributeValue::S(self.email.clone()));
-        if let Some(address) = &self.address {
-            map.insert("address".to_string(), AttributeValue::S(address.clone()));
-        }
-        if let Some(mobile) = &self.mobile {
-            map.insert("mobile".to_string(), AttributeValue::N(mobile.to_string()));
+pub use app_server_derive::ServerSerialize;
+
+#[derive(ServerSerialize)]
+#[server(remote = "Member")]
+#[allow(dead_code)]
+struct MemberDef {
+    #[server(id)]
+    id: Option<String>,
+    name: String,
+    #[server(lookup = "email_key")]
+    email: String,
+    address: Option<String>,
+    mobile: Option<PhoneNumber>,
+    subscriptions: Vec<String>,
+    #[server(default)]
+    role: Role,
+    #[server(version)]
+    version: Option<u64>,
+}
+
+#[derive(ServerSerialize)]
+#[server(remote = "Topic")]
+#[allow(dead_code)]
+struct TopicDef {
+    #[server(id)]
+    id: Option<String>,
+    name: String,
+    endpoint: String,
+    default: bool,
+    moderators: Vec<String>,
+    #[server(version)]
+    version: Option<u64>,
+}
+
+#[derive(ServerSerialize)]
+#[server(remote = "Broadcast")]
+#[allow(dead_code)]
+struct BroadcastDef {
+    #[server(id)]
+    id: Option<String>,
+    topic_id: String,
+    email_id: String,
+    status: BroadcastStatus,
+    confirmed_by: Option<String>,
+    recipients: Option<u64>,
+    progress_at: Option<u64>,
+    #[server(default)]
+    queued_members: Vec<String>,
+    #[server(version)]
+    version: Option<u64>,
+}
+
+/**
+ * A value that can be stored as a single attribute of a row
+ */
+pub trait Attribute: Sized {
+    /** Lists of this type are stored as a string set rather than a list */
+    const IN_STRING_SET: bool = false;
+
+    /** Converts to an attribute, or None if it should be left out of the row */
+    fn to_attribute(&self) -> Option<AttributeValue>;
+
+    /** Reads the attribute, or returns what kind of attribute was expected */
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str>;
+}
+
+impl Attribute for String {
+    const IN_STRING_SET: bool = true;
+
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::S(self.clone()))
+    }
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        attribute.as_s().cloned().map_err(|_| "a string")
+    }
+}
+
+impl Attribute for bool {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::Bool(*self))
+    }
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        attribute.as_bool().cloned().map_err(|_| "a boolean")
+    }
+}
+
+macro_rules! number_attribute {
+    ($($t:ty),*) => {
+        $(impl Attribute for $t {
+            fn to_attribute(&self) -> Option<AttributeValue> {
+                Some(AttributeValue::N(self.to_string()))
+            }
+
+            fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+                match attribute.as_n().map(|n| n.parse()) {
+                    Ok(Ok(n)) => Ok(n),
+                    _ => Err("a number"),
+                }
+            }
+        })*
+    };
+}
+
+number_attribute!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Attribute for PhoneNumber {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::S(self.as_str().to_string()))
+    }
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        match attribute {
+            AttributeValue::S(text) => Ok(PhoneNumber::new(text)),
+            // Numbers used to be stored as plain digits, which can be read but aren't valid until they are fixed
+            AttributeValue::N(digits) => Ok(PhoneNumber::new(digits)),
+            _ => Err("a phone number"),
         }
-        map.insert("subscriptions".to_string(), AttributeValue::Ss(self.subscriptions.clone()));
-        return map;
     }
+}
 
-    fn id(&self) -> Option<&str> {
-        self.id.as_ref().map(|id| &id[..])
+impl Attribute for Role {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::S(self.name().to_string()))
     }
 
-    fn set_id(&mut self, id: String) -> &mut Self {
-        self.id = Some(id);
-        self
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        attribute.as_s().ok().and_then(|name| Role::from_name(name)).ok_or("a role")
     }
 }
 
-impl ServerSerialize for Topic {
+impl Attribute for BroadcastStatus {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::S(self.name().to_string()))
+    }
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        attribute.as_s().ok().and_then(|name| BroadcastStatus::from_name(name)).ok_or("a broadcast status")
+    }
+}
 
-    fn from_row(data: &HashMap<String, AttributeValue>) -> Result<Self, RuntimeError> {
-        let id = read_string(data, "id")?.to_string();
-        let name = read_string(data, "name")?.to_string();
-        let endpoint = read_string(data, "endpoint")?.to_string();
-        let default = read_bool(data, "default")?;
-        Ok(Topic {
-            id: Some(id),
-            name,
-            endpoint,
-            default,
-        })
+impl<T: Attribute> Attribute for Option<T> {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        self.as_ref().and_then(|value| value.to_attribute())
     }
-    
-    fn into_row(&self) -> HashMap<String, AttributeValue> {
-        let mut map = HashMap::new();
-        if let Some(id) = &self.id {
-            map.insert("id".to_string(), AttributeValue::S(id.clone()));
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        match attribute {
+            AttributeValue::Null(_) => Ok(None),
+            attribute => T::from_attribute(attribute).map(Some),
         }
-        map.insert("name".to_string(), AttributeValue::S(self.name.clone()));
-        map.insert("endpoint".to_string(), AttributeValue::S(self.endpoint.clone()));
-        map.insert("default".to_string(), AttributeValue::Bool(self.default));
-        return map;
     }
+}
 
-    fn id(&self) -> Option<&str> {
-        self.id.as_ref().map(|id| &id[..])
+impl<T: Attribute> Attribute for Vec<T> {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        let values = self.iter().filter_map(|value| value.to_attribute());
+        if T::IN_STRING_SET {
+            // DynamoDB can't store an empty set, or one with the same string twice
+            let mut seen = HashSet::new();
+            let strings: Vec<String> = values.filter_map(|value| value.as_s().ok().cloned())
+                .filter(|string| seen.insert(string.clone()))
+                .collect();
+            Some(AttributeValue::Ss(strings)).filter(|_| !self.is_empty())
+        } else {
+            Some(AttributeValue::L(values.collect()))
+        }
     }
 
-    fn set_id(&mut self, id: String) -> &mut Self {
-        self.id = Some(id);
-        self
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        match attribute {
+            AttributeValue::Ss(strings) => strings.iter()
+                .map(|string| T::from_attribute(&AttributeValue::S(string.clone())))
+                .collect(),
+            AttributeValue::L(list) => list.iter().map(T::from_attribute).collect(),
+            _ => Err("a list"),
+        }
     }
 }
 
-fn read_string<'a>(data: &'a HashMap<String, AttributeValue>, key: &str) -> Result<&'a str, RuntimeError> {
-    match data.get(key) {
-        Some(attribute) => match attribute.as_s() {
-            Ok(string) => Ok(&string[..]),
-            Err(_) => {
-                let msg = "Key requires a string: ".to_string() + key;
-                return Err(RuntimeError::from_string(msg))
-            },
-        },
-        None => {
-            let msg = "No value for: ".to_string() + key;
-            return Err(RuntimeError::from_string(msg))
-        },
+impl<T: Attribute> Attribute for HashMap<String, T> {
+    fn to_attribute(&self) -> Option<AttributeValue> {
+        Some(AttributeValue::M(self.iter()
+            .filter_map(|(key, value)| value.to_attribute().map(|value| (key.clone(), value)))
+            .collect()))
+    }
+
+    fn from_attribute(attribute: &AttributeValue) -> Result<Self, &'static str> {
+        match attribute {
+            AttributeValue::M(map) => map.iter()
+                .map(|(key, value)| T::from_attribute(value).map(|value| (key.clone(), value)))
+                .collect(),
+            _ => Err("a map"),
+        }
     }
 }
 
-fn read_string_optional<'a>(data: &'a HashMap<String, AttributeValue>, key: &str) -> Option<&'a str> {
+/**
+ * Reads a required attribute from the row
+ */
+pub fn read_attribute<T: Attribute>(data: &HashMap<String, AttributeValue>, key: &str) -> Result<T, ApiError> {
     match data.get(key) {
-        Some(attribute) => match attribute.as_s() {
-            Ok(string) => Some(&string[..]),
-            Err(_) => None,
-        },
-        None => None,
+        Some(attribute) => T::from_attribute(attribute).map_err(|expected| {
+            ApiError::Internal(format!("Key requires {}: {}", expected, key))
+        }),
+        None => Err(ApiError::Internal("No value for: ".to_string() + key)),
     }
 }
 
-fn read_bool(data: &HashMap<String, AttributeValue>, key: &str) -> Result<bool, RuntimeError> {
+/**
+ * Reads an attribute from the row, using the default value if it is missing
+ */
+pub fn read_attribute_or_default<T: Attribute + Default>(data: &HashMap<String, AttributeValue>, key: &str) -> Result<T, ApiError> {
     match data.get(key) {
-        Some(attribute) => match attribute.as_bool() {
-            Ok(&bool) => Ok(bool),
-            Err(_) => {
-                let msg = "Key requires a boolean: ".to_string() + key;
-                return Err(RuntimeError::from_string(msg))
-            },
-        },
-        None => {
-            let msg = "No value for: ".to_string() + key;
-            return Err(RuntimeError::from_string(msg))
-        },
+        Some(_) => read_attribute(data, key),
+        None => Ok(T::default()),
     }
 }
 
-fn read_integer_optional(data: &HashMap<String, AttributeValue>, key: &str) -> Option<u64> {
-    match data.get(key) {
-        Some(attribute) => match attribute.as_n() {
-            Ok(string) => match u64::from_str_radix(&string[..], 10) {
-                Ok(n) => Some(n),
-                Err(_) => None,
-            },
-            Err(_) => None,
-        },
-        None => None,
+/**
+ * Writes an attribute into the row, unless the value should be left out
+ */
+pub fn write_attribute<T: Attribute>(data: &mut HashMap<String, AttributeValue>, key: &str, value: &T) {
+    if let Some(attribute) = value.to_attribute() {
+        data.insert(key.to_string(), attribute);
     }
 }
 
-fn read_string_list<'a>(data: &'a HashMap<String, AttributeValue>, key: &str) -> Option<&'a Vec<String>> {
-    match data.get(key) {
-        Some(attribute) => match attribute.as_ss() {
-            Ok(vec) => Some(vec),
-            Err(_) => None,
-        },
-        None => None,
+/**
+ * Writes a lowercased copy of the value into the row, for finding it by whatever its case
+ */
+pub fn write_lookup(data: &mut HashMap<String, AttributeValue>, key: &str, value: &str) {
+    data.insert(key.to_string(), AttributeValue::S(value.to_lowercase()));
+}
+
+/**
+ * An item that only serializes the chosen fields (and its id).
+ * Used to send back the `fields` asked for in a ListRequest.
+ */
+#[derive(Debug, Clone)]
+pub struct Projected<T> {
+    item: T,
+    fields: Option<Arc<Vec<String>>>,
+}
+
+impl<T> Projected<T> {
+    pub fn new(item: T, fields: Option<Arc<Vec<String>>>) -> Self {
+        Projected { item, fields }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+}
+
+impl<T> Deref for Projected<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+// Documented as the whole item, since which fields are left out depends on the request
+impl<T: JsonSchema> JsonSchema for Projected<T> {
+    fn schema_name() -> Cow<'static, str> {
+        T::schema_name()
     }
-}
\ No newline at end of file
+
+    fn schema_id() -> Cow<'static, str> {
+        T::schema_id()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        T::json_schema(generator)
+    }
+}
+
+impl<T: Serialize> Serialize for Projected<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match &self.fields {
+            Some(fields) => fields,
+            None => return self.item.serialize(serializer),
+        };
+        let mut value = serde_json::to_value(&self.item).map_err(S::Error::custom)?;
+        if let Value::Object(map) = &mut value {
+            map.retain(|key, _| key == "id" || fields.contains(key));
+        }
+        value.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use app_core::{Member, Topic, PhoneNumber, Role};
+    use aws_sdk_dynamodb::types::AttributeValue;
+
+    use super::ServerSerialize;
+
+    #[derive(ServerSerialize, Debug, PartialEq, Default)]
+    struct Event {
+        #[server(id)]
+        key: Option<String>,
+        #[server(version)]
+        revision: Option<u64>,
+        #[server(rename = "title")]
+        name: String,
+        #[server(default)]
+        attendees: u32,
+        tags: Vec<String>,
+        scores: Vec<f64>,
+        extra: HashMap<String, bool>,
+    }
+
+    #[test]
+    fn test_derive_round_trip() {
+        let event = Event {
+            key: Some("1".to_string()),
+            revision: Some(2),
+            name: "Meeting".to_string(),
+            attendees: 3,
+            tags: vec!["work".to_string()],
+            scores: vec![1.5],
+            extra: HashMap::from([("online".to_string(), true)]),
+        };
+        let row = event.into_row();
+        assert_eq!(row["title"], AttributeValue::S("Meeting".to_string()));
+        assert_eq!(row["version"], AttributeValue::N("2".to_string()));
+        assert_eq!(row["tags"], AttributeValue::Ss(vec!["work".to_string()]));
+        assert_eq!(row["scores"], AttributeValue::L(vec![AttributeValue::N("1.5".to_string())]));
+        assert_eq!(Event::from_row(&row).unwrap(), event);
+        assert_eq!(Event::REQUIRED_FIELDS, &["key", "title"]);
+
+        // DynamoDB rejects string sets with the same string twice
+        let repeated = Event { tags: vec!["work".to_string(), "home".to_string(), "work".to_string()], ..event };
+        assert_eq!(repeated.into_row()["tags"], AttributeValue::Ss(vec!["work".to_string(), "home".to_string()]));
+    }
+
+    #[test]
+    fn test_derive_missing_fields() {
+        let mut row = HashMap::new();
+        row.insert("key".to_string(), AttributeValue::S("1".to_string()));
+        assert_eq!(Event::from_row(&row).unwrap_err().to_string(), "No value for: title");
+
+        row.insert("title".to_string(), AttributeValue::N("5".to_string()));
+        assert_eq!(Event::from_row(&row).unwrap_err().to_string(), "Key requires a string: title");
+
+        row.insert("title".to_string(), AttributeValue::S("Meeting".to_string()));
+        let event = Event::from_row(&row).unwrap();
+        assert_eq!(event.attendees, 0);
+        assert!(event.tags.is_empty());
+    }
+
+    #[test]
+    fn test_member_and_topic() {
+        let member = Member {
+            id: Some("m".to_string()),
+            name: "Alice".to_string(),
+            email: "alice@example.com".to_string(),
+            address: None,
+            mobile: Some(PhoneNumber::new("+61412345678")),
+            subscriptions: vec![],
+            role: Role::Moderator,
+            version: None,
+        };
+        let mut row = member.into_row();
+        assert!(!row.contains_key("address") && !row.contains_key("subscriptions"));
+        assert_eq!(row["mobile"], AttributeValue::S("+61412345678".to_string()));
+        assert_eq!(Member::from_row(&row).unwrap().mobile, member.mobile);
+        assert_eq!(row["role"], AttributeValue::S("moderator".to_string()));
+
+        // Rows from before members had roles
+        row.remove("role");
+        assert_eq!(Member::from_row(&row).unwrap().role, Role::Member);
+
+        // Rows from before phone numbers were text
+        row.insert("mobile".to_string(), AttributeValue::N("412345678".to_string()));
+        assert_eq!(Member::from_row(&row).unwrap().mobile.unwrap().as_str(), "412345678");
+
+        let topic = Topic {
+            id: Some("t".to_string()),
+            name: "News".to_string(),
+            endpoint: "news@example.com".to_string(),
+            default: true,
+            moderators: vec![],
+            version: Some(1),
+        };
+        assert!(Topic::from_row(&topic.into_row()).unwrap().default);
+    }
+}
@@ -0,0 +1,333 @@
+extern crate aws_sdk_dynamodb;
+use std::collections::HashMap;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use app_core::api::{Filter, FilterOp};
+use lambda_http::Error;
+
+mod dynamo;
+mod memory;
+
+pub use self::dynamo::DynamoStore;
+pub use self::memory::MemoryStore;
+
+/**
+ * A single row of a table, as read from or written to a store
+ */
+pub type Row = HashMap<String, AttributeValue>;
+
+/**
+ * The attribute holding the version of a row. It goes up by one each time the row is written.
+ */
+pub const VERSION: &str = "version";
+
+/**
+ * The attribute marking a row as in the trash. Holds when the row was deleted, in seconds since the unix epoch.
+ */
+pub const DELETED_AT: &str = "deleted_at";
+
+/**
+ * What the stored row's version must be for a write to go ahead
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    /** Write over whatever is stored */
+    Anything,
+    /** The stored row must have this version. None means there must be no row, or a row without a version. */
+    Version(Option<u64>),
+    /** There must be no row, or a row that isn't in the trash */
+    Live,
+}
+
+impl Expect {
+    /**
+     * Whether a write can go ahead over the stored row (if any)
+     */
+    pub fn allows(&self, stored: Option<&Row>) -> bool {
+        match self {
+            Expect::Anything => true,
+            Expect::Version(version) => stored.and_then(row_version) == *version,
+            Expect::Live => !stored.is_some_and(|row| row.contains_key(DELETED_AT)),
+        }
+    }
+}
+
+/**
+ * The result of a conditional write
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteResult {
+    /** The row was written. Holds the row that was replaced (if any). */
+    Written(Option<Row>),
+    /** The stored row didn't have the expected version. Holds the stored row (if any). */
+    Conflict(Option<Row>),
+}
+
+/**
+ * One page of rows from a table
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    /** The cursor to read the next page from, or None if this is the last page */
+    pub next_cursor: Option<String>,
+}
+
+/**
+ * Which rows to read from a table
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Scan {
+    /** The maximum number of rows to look at */
+    pub limit: Option<u32>,
+    /** Read rows after this cursor */
+    pub cursor: Option<String>,
+    /** Only rows matching every filter are returned */
+    pub filters: Vec<Filter>,
+    /** The only attributes to read from each row. All attributes if None. */
+    pub fields: Option<Vec<String>>,
+    /** Read only rows in the trash, instead of only rows not in the trash */
+    pub trashed: bool,
+}
+
+/**
+ * The most writes that can be made in one transaction
+ */
+pub const MAX_TRANSACTION_WRITES: usize = 100;
+
+/**
+ * A write that is part of a transaction
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactWrite {
+    Put { row: Row, expect: Expect },
+    Delete { id: String, expect: Expect },
+}
+
+/**
+ * The result of a transaction
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactResult {
+    /** Every write was made */
+    Written,
+    /** No writes were made. Holds, for each write, the reason it failed (or None if it was fine). */
+    Cancelled(Vec<Option<String>>),
+}
+
+/**
+ * The cancel reason for a write whose row didn't have the expected version
+ */
+pub const CONDITION_FAILED: &str = "ConditionalCheckFailed";
+
+/**
+ * The most rows that can be read in one batch
+ */
+pub const MAX_BATCH_READS: usize = 100;
+
+/**
+ * The most writes that can be made in one batch
+ */
+pub const MAX_BATCH_WRITES: usize = 25;
+
+/**
+ * A write that is part of a batch. Batched writes don't check versions or return the replaced row.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchWrite {
+    Put(Row),
+    Delete(String),
+}
+
+impl BatchWrite {
+    /**
+     * The id of the row being written
+     */
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            BatchWrite::Put(row) => row.get("id").and_then(|id| id.as_s().ok()).map(|id| &id[..]),
+            BatchWrite::Delete(id) => Some(id),
+        }
+    }
+}
+
+/**
+ * Changes to make to some attributes of a row
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RowUpdate {
+    /** Attributes to overwrite */
+    pub set: Row,
+    /** Attributes to remove */
+    pub remove: Vec<String>,
+    /** Strings to add to string set attributes */
+    pub add: HashMap<String, Vec<String>>,
+    /** Strings to take out of string set attributes */
+    pub delete: HashMap<String, Vec<String>>,
+}
+
+impl RowUpdate {
+    /**
+     * Makes the changes to the row in memory, including bumping its version
+     */
+    pub fn apply(&self, row: &mut Row) {
+        for (key, value) in &self.set {
+            row.insert(key.clone(), value.clone());
+        }
+        for key in &self.remove {
+            row.remove(key);
+        }
+        for (key, values) in &self.add {
+            let mut set = row.get(key).and_then(|set| set.as_ss().ok()).cloned().unwrap_or_default();
+            for value in values {
+                if !set.contains(value) {
+                    set.push(value.clone());
+                }
+            }
+            row.insert(key.clone(), AttributeValue::Ss(set));
+        }
+        for (key, values) in &self.delete {
+            if let Some(AttributeValue::Ss(set)) = row.get_mut(key) {
+                set.retain(|value| !values.contains(value));
+                // DynamoDB removes sets once they are empty
+                if set.is_empty() {
+                    row.remove(key);
+                }
+            }
+        }
+        let version = row_version(row).unwrap_or(0) + 1;
+        row.insert(VERSION.to_string(), AttributeValue::N(version.to_string()));
+    }
+}
+
+/**
+ * A backend that rows can be stored in. Every row is keyed by the table it is in and its "id" attribute.
+ */
+#[async_trait]
+pub trait Store: Send + Sync {
+    /**
+     * Reads a page of rows in the table, starting after the scan's cursor (if any).
+     * The limit is applied before filtering, so a page may hold fewer than `limit` rows even when more pages follow.
+     */
+    async fn scan(&self, table: &str, scan: &Scan) -> Result<Page, Error>;
+
+    /**
+     * Reads every row in the table matching the scan, fetching all pages. The scan's limit and cursor are ignored.
+     */
+    async fn scan_all(&self, table: &str, scan: &Scan) -> Result<Vec<Row>, Error> {
+        let mut scan = Scan {
+            limit: None,
+            cursor: None,
+            ..scan.clone()
+        };
+        let mut rows = vec![];
+        loop {
+            let page = self.scan(table, &scan).await?;
+            rows.extend(page.rows);
+            scan.cursor = page.next_cursor;
+            if scan.cursor.is_none() {
+                return Ok(rows);
+            }
+        }
+    }
+
+    /**
+     * Reads every row in the table
+     */
+    async fn list(&self, table: &str) -> Result<Vec<Row>, Error> {
+        self.scan_all(table, &Scan::default()).await
+    }
+
+    /**
+     * Reads a single row, or None if no row has the id
+     */
+    async fn get(&self, table: &str, id: &str) -> Result<Option<Row>, Error>;
+
+    /**
+     * Reads many rows at once, keyed by id. Ids without a row are left out.
+     */
+    async fn get_batch(&self, table: &str, ids: &[String]) -> Result<HashMap<String, Row>, Error> {
+        let mut rows = HashMap::new();
+        for id in ids {
+            if let Some(row) = self.get(table, id).await? {
+                rows.insert(id.clone(), row);
+            }
+        }
+        Ok(rows)
+    }
+
+    /**
+     * Reads the rows not in the trash whose lookup attribute (see `ServerSerialize::LOOKUPS`) has the value.
+     * Stores with indexes read them through the table's index named after the attribute, rather than every row.
+     */
+    async fn find(&self, table: &str, lookup: &str, value: &str) -> Result<Vec<Row>, Error> {
+        let scan = Scan {
+            filters: vec![Filter { field: lookup.to_string(), op: FilterOp::Equals, value: value.to_string() }],
+            ..Default::default()
+        };
+        self.scan_all(table, &scan).await
+    }
+
+    /**
+     * Writes the row (which must have an id) if the stored row has the expected version
+     */
+    async fn put(&self, table: &str, row: Row, expect: Expect) -> Result<WriteResult, Error>;
+
+    /**
+     * Changes some attributes of an existing row (if it has the expected version) and bumps its version.
+     * Gives a conflict with no row if the row doesn't exist.
+     */
+    async fn update(&self, table: &str, id: &str, update: &RowUpdate, expect: Expect) -> Result<WriteResult, Error>;
+
+    /**
+     * Removes a row, returning the row that was removed (if any)
+     */
+    async fn delete(&self, table: &str, id: &str) -> Result<Option<Row>, Error>;
+
+    /**
+     * Makes every write, or none of them if any row doesn't have the expected version.
+     * At most `MAX_TRANSACTION_WRITES` writes can be made, each to a different row.
+     */
+    async fn transact(&self, table: &str, writes: &[TransactWrite]) -> Result<TransactResult, Error>;
+
+    /**
+     * Makes up to `MAX_BATCH_WRITES` writes, each to a different row, without checking versions.
+     * Returns the ids of the writes that the store was too busy to make.
+     */
+    async fn write_batch(&self, table: &str, writes: &[BatchWrite]) -> Result<Vec<String>, Error> {
+        for write in writes {
+            match write {
+                BatchWrite::Put(row) => {
+                    self.put(table, row.clone(), Expect::Anything).await?;
+                },
+                BatchWrite::Delete(id) => {
+                    self.delete(table, id).await?;
+                },
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+/**
+ * Reads when a row was put in the trash, or None if it isn't in the trash
+ */
+pub fn row_deleted_at(row: &Row) -> Option<u64> {
+    row.get(DELETED_AT).and_then(|deleted_at| deleted_at.as_n().ok()).and_then(|deleted_at| deleted_at.parse().ok())
+}
+
+/**
+ * Reads the version of a row, or None if it has no version
+ */
+pub fn row_version(row: &Row) -> Option<u64> {
+    row.get(VERSION).and_then(|version| version.as_n().ok()).and_then(|version| version.parse().ok())
+}
+
+/**
+ * Creates the key map used to look up a row by id
+ */
+pub fn key(id: &str) -> Row {
+    let mut map = HashMap::new();
+    map.insert("id".to_string(), AttributeValue::S(id.to_string()));
+    map
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use app_core::api::FilterOp;
+use async_trait::async_trait;
+use std::time::Duration;
+use aws_sdk_dynamodb::{Client, error::SdkError, types::{AttributeValue, ReturnValue, TransactWriteItem, Put, Delete,
+    KeysAndAttributes, WriteRequest, PutRequest, DeleteRequest}};
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use lambda_http::Error;
+
+use crate::ApiError;
+use super::{Store, Row, RowUpdate, Page, Scan, Expect, WriteResult, TransactWrite, TransactResult, BatchWrite,
+    MAX_BATCH_READS, MAX_BATCH_WRITES, VERSION, DELETED_AT, key};
+
+/** How many times to send rows DynamoDB was too busy to process */
+const MAX_BATCH_ATTEMPTS: u32 = 5;
+
+/** How long to wait before the first retry of unprocessed rows. Doubles for each retry. */
+const BATCH_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/**
+ * Stores rows in DynamoDB tables
+ */
+#[derive(Clone, Debug)]
+pub struct DynamoStore {
+    client: Client,
+}
+
+impl DynamoStore {
+    pub fn new(client: Client) -> Self {
+        DynamoStore { client }
+    }
+
+    /**
+     * Connects to DynamoDB using the AWS config in the environment
+     */
+    pub async fn from_env() -> Self {
+        let config = aws_config::load_from_env().await;
+        DynamoStore::new(Client::new(&config))
+    }
+
+    /**
+     * Connects to DynamoDB at the URL, e.g. DynamoDB Local at "http://localhost:8000"
+     */
+    pub async fn from_endpoint(url: &str) -> Self {
+        let config = aws_config::load_from_env().await;
+        let config = aws_sdk_dynamodb::config::Builder::from(&config).endpoint_url(url).build();
+        DynamoStore::new(Client::from_conf(config))
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+#[async_trait]
+impl Store for DynamoStore {
+    async fn scan(&self, table: &str, scan: &Scan) -> Result<Page, Error> {
+        let expressions = ScanExpressions::new(scan);
+        let table_response = self.client.scan()
+            .table_name(table)
+            .set_limit(scan.limit.map(|limit| limit as i32))
+            .set_exclusive_start_key(scan.cursor.as_deref().map(key))
+            .set_filter_expression(expressions.filter)
+            .set_projection_expression(expressions.projection)
+            .set_expression_attribute_names(expressions.names)
+            .set_expression_attribute_values(expressions.values)
+            .send().await.map_err(ApiError::from_sdk)?;
+
+        let rows = table_response.items().map(|items| items.to_vec()).unwrap_or_default();
+        let next_cursor = table_response.last_evaluated_key()
+            .and_then(|last| last.get("id"))
+            .and_then(|id| id.as_s().ok())
+            .cloned();
+        Ok(Page { rows, next_cursor })
+    }
+
+    async fn get(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+        let table_response = self.client.get_item()
+            .table_name(table)
+            .set_key(Some(key(id)))
+            .send().await.map_err(ApiError::from_sdk)?;
+        Ok(table_response.item().cloned())
+    }
+
+    async fn get_batch(&self, table: &str, ids: &[String]) -> Result<HashMap<String, Row>, Error> {
+        let mut unique: Vec<&String> = vec![];
+        for id in ids {
+            if !unique.contains(&id) {
+                unique.push(id);
+            }
+        }
+
+        let mut rows = HashMap::new();
+        for chunk in unique.chunks(MAX_BATCH_READS) {
+            let mut keys: Vec<Row> = chunk.iter().map(|id| key(id)).collect();
+            let mut attempt = 0;
+            while !keys.is_empty() {
+                if attempt == MAX_BATCH_ATTEMPTS {
+                    return Err(ApiError::RateLimited("DynamoDB was too busy to read every row".to_string()).into());
+                }
+                if attempt > 0 {
+                    tokio::time::sleep(BATCH_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+                }
+                attempt += 1;
+
+                let request = KeysAndAttributes::builder().set_keys(Some(keys)).build();
+                let table_response = self.client.batch_get_item()
+                    .request_items(table, request)
+                    .send().await.map_err(ApiError::from_sdk)?;
+                for row in table_response.responses().and_then(|responses| responses.get(table)).cloned().unwrap_or_default() {
+                    if let Some(Ok(id)) = row.get("id").map(|id| id.as_s()) {
+                        rows.insert(id.clone(), row);
+                    }
+                }
+                keys = table_response.unprocessed_keys()
+                    .and_then(|unprocessed| unprocessed.get(table))
+                    .and_then(|unprocessed| unprocessed.keys())
+                    .map(|keys| keys.to_vec())
+                    .unwrap_or_default();
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn find(&self, table: &str, lookup: &str, value: &str) -> Result<Vec<Row>, Error> {
+        let mut rows = vec![];
+        let mut start = None;
+        loop {
+            let table_response = self.client.query()
+                .table_name(table)
+                .index_name(lookup)
+                .key_condition_expression("#lookup = :value")
+                .filter_expression("attribute_not_exists(#deleted)")
+                .expression_attribute_names("#lookup", lookup)
+                .expression_attribute_names("#deleted", DELETED_AT)
+                .expression_attribute_values(":value", AttributeValue::S(value.to_string()))
+                .set_exclusive_start_key(start)
+                .send().await.map_err(ApiError::from_sdk)?;
+            rows.extend(table_response.items().map(|items| items.to_vec()).unwrap_or_default());
+            start = table_response.last_evaluated_key().cloned();
+            if start.is_none() {
+                return Ok(rows);
+            }
+        }
+    }
+
+    async fn put(&self, table: &str, row: Row, expect: Expect) -> Result<WriteResult, Error> {
+        let id = row.get("id").and_then(|id| id.as_s().ok()).cloned().unwrap_or_default();
+        let condition = VersionCondition::new(expect);
+        let request = self.client.put_item()
+            .table_name(table)
+            .set_item(Some(row))
+            .set_condition_expression(condition.expression)
+            .set_expression_attribute_names(condition.names)
+            .set_expression_attribute_values(condition.values)
+            .return_values(ReturnValue::AllOld);
+
+        match request.send().await {
+            Ok(table_response) => Ok(WriteResult::Written(table_response.attributes().cloned())),
+            Err(SdkError::ServiceError(err)) if err.err().is_conditional_check_failed_exception() => {
+                Ok(WriteResult::Conflict(self.get(table, &id).await?))
+            },
+            Err(err) => Err(ApiError::from_sdk(err).into()),
+        }
+    }
+
+    async fn update(&self, table: &str, id: &str, update: &RowUpdate, expect: Expect) -> Result<WriteResult, Error> {
+        let expressions = UpdateExpressions::new(update, expect);
+        let request = self.client.update_item()
+            .table_name(table)
+            .set_key(Some(key(id)))
+            .update_expression(expressions.update)
+            .condition_expression(expressions.condition)
+            .set_expression_attribute_names(Some(expressions.names))
+            .set_expression_attribute_values(Some(expressions.values))
+            .return_values(ReturnValue::AllOld);
+
+        match request.send().await {
+            Ok(table_response) => Ok(WriteResult::Written(table_response.attributes().cloned())),
+            Err(SdkError::ServiceError(err)) if err.err().is_conditional_check_failed_exception() => {
+                Ok(WriteResult::Conflict(self.get(table, id).await?))
+            },
+            Err(err) => Err(ApiError::from_sdk(err).into()),
+        }
+    }
+
+    async fn delete(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+        let table_response = self.client.delete_item()
+            .table_name(table)
+            .set_key(Some(key(id)))
+            .return_values(ReturnValue::AllOld)
+            .send().await.map_err(ApiError::from_sdk)?;
+        Ok(table_response.attributes().cloned())
+    }
+
+    async fn transact(&self, table: &str, writes: &[TransactWrite]) -> Result<TransactResult, Error> {
+        let items = writes.iter().map(|write| match write {
+            TransactWrite::Put { row, expect } => {
+                let condition = VersionCondition::new(*expect);
+                let put = Put::builder()
+                    .table_name(table)
+                    .set_item(Some(row.clone()))
+                    .set_condition_expression(condition.expression)
+                    .set_expression_attribute_names(condition.names)
+                    .set_expression_attribute_values(condition.values)
+                    .build();
+                TransactWriteItem::builder().put(put).build()
+            },
+            TransactWrite::Delete { id, expect } => {
+                let condition = VersionCondition::new(*expect);
+                let delete = Delete::builder()
+                    .table_name(table)
+                    .set_key(Some(key(id)))
+                    .set_condition_expression(condition.expression)
+                    .set_expression_attribute_names(condition.names)
+                    .set_expression_attribute_values(condition.values)
+                    .build();
+                TransactWriteItem::builder().delete(delete).build()
+            },
+        }).collect();
+
+        let request = self.client.transact_write_items()
+            .set_transact_items(Some(items));
+        match request.send().await {
+            Ok(_) => Ok(TransactResult::Written),
+            Err(SdkError::ServiceError(err)) if err.err().is_transaction_canceled_exception() => {
+                let reasons = match err.err() {
+                    TransactWriteItemsError::TransactionCanceledException(cancelled) => cancelled.cancellation_reasons()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|reason| reason.code().filter(|code| *code != "None").map(|code| code.to_string()))
+                        .collect(),
+                    _ => vec![],
+                };
+                Ok(TransactResult::Cancelled(reasons))
+            },
+            Err(err) => Err(ApiError::from_sdk(err).into()),
+        }
+    }
+
+    async fn write_batch(&self, table: &str, writes: &[BatchWrite]) -> Result<Vec<String>, Error> {
+        if writes.len() > MAX_BATCH_WRITES {
+            let msg = format!("At most {} rows can be written in a batch", MAX_BATCH_WRITES);
+            return Err(ApiError::Internal(msg).into());
+        }
+        let mut requests: Vec<WriteRequest> = writes.iter().map(|write| match write {
+            BatchWrite::Put(row) => WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(row.clone())).build())
+                .build(),
+            BatchWrite::Delete(id) => WriteRequest::builder()
+                .delete_request(DeleteRequest::builder().set_key(Some(key(id))).build())
+                .build(),
+        }).collect();
+
+        let mut attempt = 0;
+        while !requests.is_empty() && attempt < MAX_BATCH_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(BATCH_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+            }
+            attempt += 1;
+
+            let table_response = self.client.batch_write_item()
+                .request_items(table, requests)
+                .send().await.map_err(ApiError::from_sdk)?;
+            requests = table_response.unprocessed_items()
+                .and_then(|unprocessed| unprocessed.get(table))
+                .cloned()
+                .unwrap_or_default();
+        }
+
+        // Anything left over couldn't be written
+        let unprocessed = requests.iter().filter_map(|request| {
+            let row = match (request.put_request(), request.delete_request()) {
+                (Some(put), _) => put.item(),
+                (_, Some(delete)) => delete.key(),
+                _ => None,
+            };
+            row.and_then(|row| row.get("id")).and_then(|id| id.as_s().ok()).cloned()
+        }).collect();
+        Ok(unprocessed)
+    }
+}
+
+/**
+ * The DynamoDB condition that checks a row has the expected version (or isn't in the trash) before writing
+ */
+#[derive(Debug, Default)]
+struct VersionCondition {
+    expression: Option<String>,
+    names: Option<HashMap<String, String>>,
+    values: Option<HashMap<String, AttributeValue>>,
+}
+
+impl VersionCondition {
+    fn new(expect: Expect) -> Self {
+        let names = Some([("#version".to_string(), VERSION.to_string())].into());
+        match expect {
+            Expect::Anything => VersionCondition::default(),
+            Expect::Version(None) => VersionCondition {
+                expression: Some("attribute_not_exists(#version)".to_string()),
+                names,
+                values: None,
+            },
+            Expect::Version(Some(version)) => VersionCondition {
+                expression: Some("#version = :version".to_string()),
+                names,
+                values: Some([(":version".to_string(), AttributeValue::N(version.to_string()))].into()),
+            },
+            Expect::Live => VersionCondition {
+                expression: Some("attribute_not_exists(#deleted)".to_string()),
+                names: Some([("#deleted".to_string(), DELETED_AT.to_string())].into()),
+                values: None,
+            },
+        }
+    }
+}
+
+/**
+ * The DynamoDB expressions that perform a scan's filters and projection
+ */
+#[derive(Debug, Default)]
+struct ScanExpressions {
+    filter: Option<String>,
+    projection: Option<String>,
+    names: Option<HashMap<String, String>>,
+    values: Option<HashMap<String, AttributeValue>>,
+}
+
+impl ScanExpressions {
+    fn new(scan: &Scan) -> Self {
+        let mut names = HashMap::new();
+        let mut values = HashMap::new();
+
+        let mut conditions: Vec<String> = scan.filters.iter().enumerate().map(|(i, filter)| {
+            let name = format!("#f{}", i);
+            let value = format!(":f{}", i);
+            names.insert(name.clone(), filter.field.clone());
+            values.insert(value.clone(), AttributeValue::S(filter.value.clone()));
+            match filter.op {
+                FilterOp::Prefix => format!("begins_with({}, {})", name, value),
+                FilterOp::Contains => format!("contains({}, {})", name, value),
+                FilterOp::Equals => {
+                    // The type of the attribute isn't known, so match any type the value could be
+                    let mut options = vec![format!("{} = {}", name, value)];
+                    if filter.value.parse::<f64>().is_ok() {
+                        values.insert(format!("{}n", value), AttributeValue::N(filter.value.clone()));
+                        options.push(format!("{} = {}n", name, value));
+                    }
+                    if let Ok(b) = filter.value.parse::<bool>() {
+                        values.insert(format!("{}b", value), AttributeValue::Bool(b));
+                        options.push(format!("{} = {}b", name, value));
+                    }
+                    format!("({})", options.join(" OR "))
+                }
+            }
+        }).collect();
+
+        names.insert("#deleted".to_string(), DELETED_AT.to_string());
+        conditions.push(match scan.trashed {
+            true => "attribute_exists(#deleted)".to_string(),
+            false => "attribute_not_exists(#deleted)".to_string(),
+        });
+
+        let projection = scan.fields.as_ref().map(|fields| {
+            fields.iter().enumerate().map(|(i, field)| {
+                let name = format!("#p{}", i);
+                names.insert(name.clone(), field.clone());
+                name
+            }).collect::<Vec<_>>().join(", ")
+        });
+
+        ScanExpressions {
+            filter: Some(conditions.join(" AND ")).filter(|filter| !filter.is_empty()),
+            projection,
+            names: Some(names).filter(|names| !names.is_empty()),
+            values: Some(values).filter(|values| !values.is_empty()),
+        }
+    }
+}
+
+/**
+ * The DynamoDB expressions that make the changes of a row update
+ */
+#[derive(Debug)]
+struct UpdateExpressions {
+    update: String,
+    condition: String,
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+}
+
+impl UpdateExpressions {
+    fn new(update: &RowUpdate, expect: Expect) -> Self {
+        let mut names = HashMap::new();
+        let mut values = HashMap::new();
+        names.insert("#id".to_string(), "id".to_string());
+        names.insert("#version".to_string(), VERSION.to_string());
+        values.insert(":one".to_string(), AttributeValue::N("1".to_string()));
+
+        let mut set = vec![];
+        for (i, (key, value)) in update.set.iter().enumerate() {
+            names.insert(format!("#s{}", i), key.clone());
+            values.insert(format!(":s{}", i), value.clone());
+            set.push(format!("#s{} = :s{}", i, i));
+        }
+        let mut remove = vec![];
+        for (i, key) in update.remove.iter().enumerate() {
+            names.insert(format!("#r{}", i), key.clone());
+            remove.push(format!("#r{}", i));
+        }
+        let mut add = vec!["#version :one".to_string()];
+        for (i, (key, strings)) in update.add.iter().enumerate() {
+            names.insert(format!("#a{}", i), key.clone());
+            values.insert(format!(":a{}", i), AttributeValue::Ss(strings.clone()));
+            add.push(format!("#a{} :a{}", i, i));
+        }
+        let mut delete = vec![];
+        for (i, (key, strings)) in update.delete.iter().enumerate() {
+            names.insert(format!("#d{}", i), key.clone());
+            values.insert(format!(":d{}", i), AttributeValue::Ss(strings.clone()));
+            delete.push(format!("#d{} :d{}", i, i));
+        }
+
+        let mut clauses = vec![];
+        for (action, parts) in [("SET", set), ("REMOVE", remove), ("ADD", add), ("DELETE", delete)] {
+            if !parts.is_empty() {
+                clauses.push(format!("{} {}", action, parts.join(", ")));
+            }
+        }
+
+        let condition = match expect {
+            Expect::Anything => "attribute_exists(#id)".to_string(),
+            Expect::Version(None) => "attribute_exists(#id) AND attribute_not_exists(#version)".to_string(),
+            Expect::Version(Some(version)) => {
+                values.insert(":version".to_string(), AttributeValue::N(version.to_string()));
+                "attribute_exists(#id) AND #version = :version".to_string()
+            },
+            Expect::Live => {
+                names.insert("#deleted".to_string(), DELETED_AT.to_string());
+                "attribute_exists(#id) AND attribute_not_exists(#deleted)".to_string()
+            },
+        };
+
+        UpdateExpressions {
+            update: clauses.join(" "),
+            condition,
+            names,
+            values,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use app_core::api::{Filter, FilterOp};
+    use aws_sdk_dynamodb::types::AttributeValue;
+
+    use crate::store::{Scan, RowUpdate, Expect};
+    use super::{ScanExpressions, UpdateExpressions};
+
+    #[test]
+    fn test_scan_expressions() {
+        let expressions = ScanExpressions::new(&Scan::default());
+        assert_eq!(expressions.filter.unwrap(), "attribute_not_exists(#deleted)");
+        assert!(expressions.projection.is_none() && expressions.values.is_none());
+
+        let scan = Scan {
+            filters: vec![
+                Filter { field: "name".to_string(), op: FilterOp::Prefix, value: "Al".to_string() },
+                Filter { field: "mobile".to_string(), op: FilterOp::Equals, value: "123".to_string() },
+            ],
+            fields: Some(vec!["id".to_string(), "name".to_string()]),
+            ..Default::default()
+        };
+        let expressions = ScanExpressions::new(&scan);
+        assert_eq!(expressions.filter.unwrap(), "begins_with(#f0, :f0) AND (#f1 = :f1 OR #f1 = :f1n) AND attribute_not_exists(#deleted)");
+        assert_eq!(expressions.projection.unwrap(), "#p0, #p1");
+        assert_eq!(expressions.names.unwrap().len(), 5);
+        assert_eq!(expressions.values.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_update_expressions() {
+        let update = RowUpdate {
+            set: [("name".to_string(), AttributeValue::S("Alice".to_string()))].into(),
+            remove: vec!["address".to_string()],
+            add: [("subscriptions".to_string(), vec!["news".to_string()])].into(),
+            delete: [("tags".to_string(), vec!["old".to_string()])].into(),
+        };
+        let expressions = UpdateExpressions::new(&update, Expect::Version(Some(4)));
+        assert_eq!(expressions.update, "SET #s0 = :s0 REMOVE #r0 ADD #version :one, #a0 :a0 DELETE #d0 :d0");
+        assert_eq!(expressions.condition, "attribute_exists(#id) AND #version = :version");
+        assert_eq!(expressions.names.len(), 6);
+        assert_eq!(expressions.values.len(), 5);
+
+        let expressions = UpdateExpressions::new(&update, Expect::Live);
+        assert_eq!(expressions.condition, "attribute_exists(#id) AND attribute_not_exists(#deleted)");
+        assert_eq!(expressions.names["#deleted"], "deleted_at");
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use lambda_http::Error;
+
+use crate::ApiError;
+use crate::query::{matches, project};
+use super::{Store, Row, RowUpdate, Page, Scan, Expect, WriteResult, TransactWrite, TransactResult, CONDITION_FAILED, DELETED_AT};
+
+/**
+ * Stores rows in memory. Useful for tests and running without AWS.
+ * Rows in each table are kept ordered by id.
+ */
+#[derive(Default, Debug)]
+pub struct MemoryStore {
+    tables: Mutex<HashMap<String, BTreeMap<String, Row>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+fn row_id(row: &Row) -> Result<String, ApiError> {
+    match row.get("id").map(|id| id.as_s()) {
+        Some(Ok(id)) => Ok(id.clone()),
+        _ => Err(ApiError::Internal("Row requires a string id".to_string())),
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn scan(&self, table: &str, scan: &Scan) -> Result<Page, Error> {
+        let tables = self.tables.lock().unwrap();
+        let rows = match tables.get(table) {
+            Some(rows) => rows,
+            None => return Ok(Page::default()),
+        };
+
+        let start = match &scan.cursor {
+            Some(cursor) => Bound::Excluded(cursor.clone()),
+            None => Bound::Unbounded,
+        };
+        let mut remaining = rows.range((start, Bound::Unbounded));
+        let page: Vec<(&String, &Row)> = match scan.limit {
+            Some(limit) => remaining.by_ref().take(limit as usize).collect(),
+            None => remaining.by_ref().collect(),
+        };
+
+        let next_cursor = match (page.last(), remaining.next()) {
+            (Some((id, _)), Some(_)) => Some(id.to_string()),
+            _ => None,
+        };
+        let rows = page.into_iter()
+            .map(|(_, row)| row)
+            .filter(|row| row.contains_key(DELETED_AT) == scan.trashed && matches(row, &scan.filters))
+            .map(|row| {
+                let mut row = row.clone();
+                if let Some(fields) = &scan.fields {
+                    project(&mut row, fields);
+                }
+                row
+            })
+            .collect();
+        Ok(Page {
+            rows,
+            next_cursor,
+        })
+    }
+
+    async fn get(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.get(table).and_then(|rows| rows.get(id)).cloned())
+    }
+
+    async fn put(&self, table: &str, row: Row, expect: Expect) -> Result<WriteResult, Error> {
+        let id = row_id(&row)?;
+        let mut tables = self.tables.lock().unwrap();
+        let rows = tables.entry(table.to_string()).or_default();
+        let stored = rows.get(&id);
+        if !expect.allows(stored) {
+            return Ok(WriteResult::Conflict(stored.cloned()));
+        }
+        Ok(WriteResult::Written(rows.insert(id, row)))
+    }
+
+    async fn update(&self, table: &str, id: &str, update: &RowUpdate, expect: Expect) -> Result<WriteResult, Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let row = match tables.get_mut(table).and_then(|rows| rows.get_mut(id)) {
+            Some(row) => row,
+            None => return Ok(WriteResult::Conflict(None)),
+        };
+        if !expect.allows(Some(row)) {
+            return Ok(WriteResult::Conflict(Some(row.clone())));
+        }
+        let old_row = row.clone();
+        update.apply(row);
+        Ok(WriteResult::Written(Some(old_row)))
+    }
+
+    async fn delete(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+        let mut tables = self.tables.lock().unwrap();
+        Ok(tables.get_mut(table).and_then(|rows| rows.remove(id)))
+    }
+
+    async fn transact(&self, table: &str, writes: &[TransactWrite]) -> Result<TransactResult, Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let rows = tables.entry(table.to_string()).or_default();
+
+        // Check every write can be made before making any
+        let mut reasons = vec![];
+        for write in writes {
+            let (id, expect) = match write {
+                TransactWrite::Put { row, expect } => (row_id(row)?, expect),
+                TransactWrite::Delete { id, expect } => (id.clone(), expect),
+            };
+            let failed = !expect.allows(rows.get(&id));
+            reasons.push(Some(CONDITION_FAILED.to_string()).filter(|_| failed));
+        }
+        if reasons.iter().any(|reason| reason.is_some()) {
+            return Ok(TransactResult::Cancelled(reasons));
+        }
+
+        for write in writes {
+            match write {
+                TransactWrite::Put { row, .. } => { rows.insert(row_id(row)?, row.clone()); },
+                TransactWrite::Delete { id, .. } => { rows.remove(id); },
+            }
+        }
+        Ok(TransactResult::Written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::types::AttributeValue;
+
+    use app_core::api::{Filter, FilterOp};
+
+    use crate::store::{Store, Scan, Expect, WriteResult, RowUpdate, TransactWrite, TransactResult, CONDITION_FAILED, key};
+    use super::MemoryStore;
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let store = MemoryStore::new();
+        let mut row = key("a");
+        row.insert("name".to_string(), AttributeValue::S("Alice".to_string()));
+
+        assert_eq!(store.put("people", row.clone(), Expect::Anything).await.unwrap(), WriteResult::Written(None));
+        assert_eq!(store.get("people", "a").await.unwrap(), Some(row.clone()));
+        assert_eq!(store.put("people", key("a"), Expect::Anything).await.unwrap(), WriteResult::Written(Some(row)));
+        assert_eq!(store.list("people").await.unwrap().len(), 1);
+        assert!(store.list("other").await.unwrap().is_empty());
+
+        assert_eq!(store.delete("people", "a").await.unwrap(), Some(key("a")));
+        assert!(store.get("people", "a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scan_pages() {
+        let store = MemoryStore::new();
+        for id in ["a", "b", "c"] {
+            store.put("letters", key(id), Expect::Anything).await.unwrap();
+        }
+
+        let page = store.scan("letters", &Scan { limit: Some(2), ..Default::default() }).await.unwrap();
+        assert_eq!(page.rows, vec![key("a"), key("b")]);
+        assert_eq!(page.next_cursor.as_deref(), Some("b"));
+
+        let page = store.scan("letters", &Scan { limit: Some(2), cursor: page.next_cursor, ..Default::default() }).await.unwrap();
+        assert_eq!(page.rows, vec![key("c")]);
+        assert!(page.next_cursor.is_none());
+
+        assert_eq!(store.list("letters").await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_scan_filter_and_project() {
+        let store = MemoryStore::new();
+        for (id, name) in [("a", "Alice"), ("b", "Bob"), ("c", "Alan")] {
+            let mut row = key(id);
+            row.insert("name".to_string(), AttributeValue::S(name.to_string()));
+            row.insert("email".to_string(), AttributeValue::S(format!("{}@example.com", id)));
+            store.put("people", row, Expect::Anything).await.unwrap();
+        }
+
+        let scan = Scan {
+            filters: vec![Filter { field: "name".to_string(), op: FilterOp::Prefix, value: "Al".to_string() }],
+            fields: Some(vec!["id".to_string(), "name".to_string()]),
+            ..Default::default()
+        };
+        let rows = store.scan_all("people", &scan).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.len() == 2 && !row.contains_key("email")));
+    }
+
+    #[tokio::test]
+    async fn test_put_requires_id() {
+        let store = MemoryStore::new();
+        assert!(store.put("people", Default::default(), Expect::Anything).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_expected_version() {
+        let store = MemoryStore::new();
+        let mut row = key("a");
+        row.insert("version".to_string(), AttributeValue::N("1".to_string()));
+
+        assert_eq!(store.put("people", row.clone(), Expect::Version(Some(1))).await.unwrap(), WriteResult::Conflict(None));
+        assert_eq!(store.put("people", row.clone(), Expect::Version(None)).await.unwrap(), WriteResult::Written(None));
+        assert_eq!(store.put("people", key("a"), Expect::Version(None)).await.unwrap(), WriteResult::Conflict(Some(row.clone())));
+        assert_eq!(store.put("people", key("a"), Expect::Version(Some(1))).await.unwrap(), WriteResult::Written(Some(row)));
+    }
+
+    #[tokio::test]
+    async fn test_put_expected_live() {
+        let store = MemoryStore::new();
+        let mut trashed = key("a");
+        trashed.insert("deleted_at".to_string(), AttributeValue::N("100".to_string()));
+
+        assert_eq!(store.put("people", key("a"), Expect::Live).await.unwrap(), WriteResult::Written(None));
+        assert_eq!(store.put("people", trashed.clone(), Expect::Live).await.unwrap(), WriteResult::Written(Some(key("a"))));
+        assert_eq!(store.put("people", key("a"), Expect::Live).await.unwrap(), WriteResult::Conflict(Some(trashed.clone())));
+        assert_eq!(store.update("people", "a", &RowUpdate::default(), Expect::Live).await.unwrap(), WriteResult::Conflict(Some(trashed)));
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let store = MemoryStore::new();
+        let mut row = key("a");
+        row.insert("tags".to_string(), AttributeValue::Ss(vec!["x".to_string(), "y".to_string()]));
+        row.insert("labels".to_string(), AttributeValue::Ss(vec!["p".to_string()]));
+        store.put("people", row.clone(), Expect::Anything).await.unwrap();
+
+        let update = RowUpdate {
+            set: [("name".to_string(), AttributeValue::S("Alice".to_string()))].into(),
+            add: [("tags".to_string(), vec!["z".to_string()])].into(),
+            delete: [("labels".to_string(), vec!["p".to_string()])].into(),
+            ..Default::default()
+        };
+        assert_eq!(store.update("people", "a", &update, Expect::Version(Some(3))).await.unwrap(), WriteResult::Conflict(Some(row.clone())));
+        assert_eq!(store.update("people", "b", &update, Expect::Anything).await.unwrap(), WriteResult::Conflict(None));
+        assert_eq!(store.update("people", "a", &update, Expect::Anything).await.unwrap(), WriteResult::Written(Some(row)));
+
+        let row = store.get("people", "a").await.unwrap().unwrap();
+        assert_eq!(row["name"], AttributeValue::S("Alice".to_string()));
+        assert_eq!(row["tags"], AttributeValue::Ss(vec!["x".to_string(), "y".to_string(), "z".to_string()]));
+        assert!(!row.contains_key("labels"));
+        assert_eq!(row["version"], AttributeValue::N("1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_transact() {
+        let store = MemoryStore::new();
+        store.put("people", key("a"), Expect::Anything).await.unwrap();
+
+        let writes = vec![
+            TransactWrite::Put { row: key("b"), expect: Expect::Version(None) },
+            TransactWrite::Delete { id: "a".to_string(), expect: Expect::Version(Some(1)) },
+        ];
+        let result = store.transact("people", &writes).await.unwrap();
+        assert_eq!(result, TransactResult::Cancelled(vec![None, Some(CONDITION_FAILED.to_string())]));
+        assert!(store.get("people", "b").await.unwrap().is_none());
+
+        let writes = vec![
+            TransactWrite::Put { row: key("b"), expect: Expect::Version(None) },
+            TransactWrite::Delete { id: "a".to_string(), expect: Expect::Anything },
+        ];
+        assert_eq!(store.transact("people", &writes).await.unwrap(), TransactResult::Written);
+        assert_eq!(store.list("people").await.unwrap(), vec![key("b")]);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use app_core::{Member, Topic, api::{AuditAction, UnsubscribeRequest, UnsubscribeResponse}};
+use lambda_http::{Error, Request, http::Method};
+
+use crate::{ApiError, audit::{Audit, Change}, config::AppConfig, links::{LinkAction, LinkSigner}, serialize::ServerSerialize, store::{Store, Row, RowUpdate, Expect, WriteResult, DELETED_AT}};
+
+/** Who the audit log says made the change, since whoever clicked the link isn't signed in */
+const ACTOR: &str = "unsubscribe link";
+
+/** The path mail apps post to when their unsubscribe button is pressed */
+pub const ONE_CLICK_PATH: &str = "/members-unsubscribe-one-click";
+
+/** The mailbox at the sending domain that people can email to unsubscribe */
+const MAILBOX: &str = "unsubscribe";
+
+/**
+ * Takes the topic out of the member's subscriptions, if the token is from their unsubscribe link.
+ * Unsubscribing twice is fine, and only the first time is recorded in the audit log.
+ */
+pub async fn unsubscribe(config: &AppConfig, store: &dyn Store, input: UnsubscribeRequest) -> Result<UnsubscribeResponse, Error> {
+    LinkSigner::from_config(config).verify(&input.token, LinkAction::Unsubscribe, &input.topic_id, &input.member_id)?;
+    let topic = Topic::from_row(&live_row(store, &config.topics_table, &input.topic_id, "topic").await?)?;
+    let member = Member::from_row(&live_row(store, &config.members_table, &input.member_id, "member").await?)?;
+
+    let subscriptions: Vec<String> = member.subscriptions.into_iter()
+        .filter(|subscription| topic.has_subscription(subscription))
+        .collect();
+    if subscriptions.is_empty() {
+        return Ok(UnsubscribeResponse { topic: topic.name, unsubscribed: false });
+    }
+
+    let update = RowUpdate {
+        delete: [("subscriptions".to_string(), subscriptions)].into(),
+        ..Default::default()
+    };
+    // The member may have been trashed since it was read
+    let before = match store.update(&config.members_table, &input.member_id, &update, Expect::Live).await? {
+        WriteResult::Written(before) => before.unwrap_or_default(),
+        WriteResult::Conflict(_) => return Err(ApiError::NotFound(format!("No member with id: {}", input.member_id)).into()),
+    };
+    let mut after = before.clone();
+    update.apply(&mut after);
+    let audit = Audit::new(store, &config.audit_table, ACTOR);
+    audit.record(&config.members_table, vec![Change {
+        action: AuditAction::Update,
+        id: &input.member_id,
+        before: Some(before),
+        after: Some(after),
+    }]).await;
+    Ok(UnsubscribeResponse { topic: topic.name, unsubscribed: true })
+}
+
+/**
+ * Unsubscribes when a mail app's unsubscribe button posts to the `one_click_url` (RFC 8058).
+ * Only posts unsubscribe, so link checkers that open the URL don't.
+ */
+pub async fn one_click(config: &AppConfig, store: &dyn Store, event: Request) -> Result<UnsubscribeResponse, Error> {
+    if event.method() != Method::POST {
+        return Err(ApiError::Validation("Unsubscribe by posting to this link".to_string()).into());
+    }
+    // Mail apps post the form "List-Unsubscribe=One-Click", which a browser opening the link never sends
+    let form: HashMap<String, String> = form_urlencoded::parse(event.body().as_ref()).into_owned().collect();
+    if form.get("List-Unsubscribe").map(|value| &value[..]) != Some("One-Click") {
+        return Err(ApiError::Validation("Unsubscribe by posting List-Unsubscribe=One-Click to this link".to_string()).into());
+    }
+    let query: HashMap<String, String> = form_urlencoded::parse(event.uri().query().unwrap_or_default().as_bytes()).into_owned().collect();
+    let value = |name: &str| query.get(name).cloned().ok_or_else(|| ApiError::Validation(format!("The link has no {}", name)));
+    let input = UnsubscribeRequest { member_id: value("member")?, topic_id: value("topic")?, token: value("token")? };
+    unsubscribe(config, store, input).await
+}
+
+/**
+ * The URL mail apps post to when their unsubscribe button is pressed
+ */
+pub fn one_click_url(config: &AppConfig, request: &UnsubscribeRequest) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("member", &request.member_id)
+        .append_pair("topic", &request.topic_id)
+        .append_pair("token", &request.token)
+        .finish();
+    format!("{}{}?{}", config.api_base, ONE_CLICK_PATH, query)
+}
+
+/**
+ * The address to email to unsubscribe, for mail apps that can't post
+ */
+pub fn unsubscribe_address(config: &AppConfig) -> String {
+    format!("{}@{}", MAILBOX, config.sending_domain)
+}
+
+/**
+ * A link that writes an email to the `unsubscribe_address`, with the request in its subject
+ */
+pub fn mailto_url(config: &AppConfig, request: &UnsubscribeRequest) -> String {
+    let subject = format!("{} {} {} {}", MAILBOX, request.member_id, request.topic_id, request.token);
+    format!("mailto:{}?subject={}", unsubscribe_address(config), form_urlencoded::byte_serialize(subject.as_bytes()).collect::<String>().replace('+', "%20"))
+}
+
+/**
+ * Reads the request back out of the subject of an email written by a `mailto_url`
+ */
+pub fn from_subject(subject: &str) -> Option<UnsubscribeRequest> {
+    match subject.split_whitespace().collect::<Vec<_>>()[..] {
+        [mailbox, member_id, topic_id, token] if mailbox.eq_ignore_ascii_case(MAILBOX) => Some(UnsubscribeRequest {
+            member_id: member_id.to_string(),
+            topic_id: topic_id.to_string(),
+            token: token.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/**
+ * Reads the row, unless it doesn't exist or is in the trash
+ */
+async fn live_row(store: &dyn Store, table: &str, id: &str, kind: &str) -> Result<Row, Error> {
+    match store.get(table, id).await? {
+        Some(row) if !row.contains_key(DELETED_AT) => Ok(row),
+        _ => Err(ApiError::NotFound(format!("No {} with id: {}", kind, id)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use app_core::{Member, Role, Topic, api::{ListAuditRequest, UnsubscribeRequest}};
+    use async_trait::async_trait;
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use lambda_http::{Body, Error, Request, http::Method};
+
+    use crate::{ApiError, audit::list_audit, config::AppConfig, links::{LinkAction, LinkSigner, UNSUBSCRIBE_LINK_LIFETIME}, serialize::ServerSerialize,
+        store::{Expect, MemoryStore, Store, Scan, Page, Row, RowUpdate, WriteResult, TransactWrite, TransactResult, BatchWrite, DELETED_AT}};
+    use super::{from_subject, mailto_url, one_click, one_click_url, unsubscribe};
+
+    async fn add_member(store: &dyn Store) {
+        let member = Member {
+            id: Some("m1".to_string()),
+            name: "Ann".to_string(),
+            email: "ann@example.com".to_string(),
+            address: None,
+            mobile: None,
+            subscriptions: vec!["news".to_string(), "events".to_string()],
+            role: Role::Member,
+            version: Some(1),
+        };
+        let topic = Topic { id: Some("t1".to_string()), name: "News".to_string(), endpoint: "news@example.com".to_string(), default: false, moderators: vec![], version: Some(1) };
+        store.put("members", member.into_row(), Expect::Anything).await.unwrap();
+        store.put("topics", topic.into_row(), Expect::Anything).await.unwrap();
+    }
+
+    /**
+     * A memory store where members are trashed just after they are read, as if by an admin at the same moment
+     */
+    struct TrashingStore {
+        inner: MemoryStore,
+    }
+
+    #[async_trait]
+    impl Store for TrashingStore {
+        async fn scan(&self, table: &str, scan: &Scan) -> Result<Page, Error> {
+            self.inner.scan(table, scan).await
+        }
+
+        async fn get(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+            let row = self.inner.get(table, id).await?;
+            if table == "members" {
+                let trash = RowUpdate { set: [(DELETED_AT.to_string(), AttributeValue::N("1".to_string()))].into(), ..Default::default() };
+                self.inner.update(table, id, &trash, Expect::Anything).await?;
+            }
+            Ok(row)
+        }
+
+        async fn get_batch(&self, table: &str, ids: &[String]) -> Result<HashMap<String, Row>, Error> {
+            self.inner.get_batch(table, ids).await
+        }
+
+        async fn put(&self, table: &str, row: Row, expect: Expect) -> Result<WriteResult, Error> {
+            self.inner.put(table, row, expect).await
+        }
+
+        async fn update(&self, table: &str, id: &str, update: &RowUpdate, expect: Expect) -> Result<WriteResult, Error> {
+            self.inner.update(table, id, update, expect).await
+        }
+
+        async fn delete(&self, table: &str, id: &str) -> Result<Option<Row>, Error> {
+            self.inner.delete(table, id).await
+        }
+
+        async fn transact(&self, table: &str, writes: &[TransactWrite]) -> Result<TransactResult, Error> {
+            self.inner.transact(table, writes).await
+        }
+
+        async fn write_batch(&self, table: &str, writes: &[BatchWrite]) -> Result<Vec<String>, Error> {
+            self.inner.write_batch(table, writes).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe() {
+        let config = AppConfig::for_tests();
+        let store = MemoryStore::new();
+        add_member(&store).await;
+        let token = |member_id: &str| LinkSigner::from_config(&config).sign(LinkAction::Unsubscribe, "t1", member_id, UNSUBSCRIBE_LINK_LIFETIME).unwrap();
+        let request = || UnsubscribeRequest { member_id: "m1".to_string(), topic_id: "t1".to_string(), token: token("m1") };
+
+        let response = unsubscribe(&config, &store, request()).await.unwrap();
+        assert_eq!(response.topic, "News");
+        assert!(response.unsubscribed);
+        let member = Member::from_row(&store.get("members", "m1").await.unwrap().unwrap()).unwrap();
+        assert_eq!(member.subscriptions, vec!["events"]);
+        assert_eq!(member.version, Some(2));
+
+        // Clicking the link again changes nothing
+        assert!(!unsubscribe(&config, &store, request()).await.unwrap().unsubscribed);
+        let audit = list_audit::<Member>(&store, ListAuditRequest::default(), "audit", "members").await.unwrap();
+        assert_eq!(audit.entries.len(), 1);
+        assert_eq!(audit.entries[0].actor, "unsubscribe link");
+
+        let err = unsubscribe(&config, &store, UnsubscribeRequest { member_id: "m2".to_string(), topic_id: "t1".to_string(), token: token("m2") }).await.unwrap_err();
+        assert!(matches!(ApiError::from_error(err), ApiError::NotFound(_)));
+
+        // Knowing the ids isn't enough to unsubscribe someone
+        let forged = UnsubscribeRequest { member_id: "m2".to_string(), topic_id: "t1".to_string(), token: token("m1") };
+        let err = unsubscribe(&config, &store, forged).await.unwrap_err();
+        assert!(matches!(ApiError::from_error(err), ApiError::Forbidden(_)));
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_trashed() {
+        let config = AppConfig::for_tests();
+        let store = TrashingStore { inner: MemoryStore::new() };
+        add_member(&store.inner).await;
+        let token = LinkSigner::from_config(&config).sign(LinkAction::Unsubscribe, "t1", "m1", UNSUBSCRIBE_LINK_LIFETIME).unwrap();
+
+        // A member trashed between reading and writing them isn't brought back by the unsubscribe
+        let err = unsubscribe(&config, &store, UnsubscribeRequest { member_id: "m1".to_string(), topic_id: "t1".to_string(), token }).await.unwrap_err();
+        assert!(matches!(ApiError::from_error(err), ApiError::NotFound(_)));
+        let member = Member::from_row(&store.inner.get("members", "m1").await.unwrap().unwrap()).unwrap();
+        assert_eq!(member.subscriptions.len(), 2);
+        assert!(list_audit::<Member>(&store.inner, ListAuditRequest::default(), "audit", "members").await.unwrap().entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_one_click() {
+        let config = AppConfig::for_tests();
+        let store = MemoryStore::new();
+        add_member(&store).await;
+        let token = LinkSigner::from_config(&config).sign(LinkAction::Unsubscribe, "t1", "m1", UNSUBSCRIBE_LINK_LIFETIME).unwrap();
+        let input = UnsubscribeRequest { member_id: "m1".to_string(), topic_id: "t1".to_string(), token };
+
+        let url = one_click_url(&config, &input);
+        assert!(url.starts_with("https://sinln.mdsimmo.com/members-unsubscribe-one-click?member=m1&topic=t1&token="));
+        let request = |method: Method, body: &str| {
+            let mut request = Request::new(Body::Text(body.to_string()));
+            *request.method_mut() = method;
+            *request.uri_mut() = url.parse().unwrap();
+            request
+        };
+        // Link checkers opening the link don't unsubscribe, and nor does posting anything but the one-click form
+        assert!(one_click(&config, &store, request(Method::GET, "List-Unsubscribe=One-Click")).await.is_err());
+        for body in ["", "List-Unsubscribe=Other", "unsubscribe=1"] {
+            let err = one_click(&config, &store, request(Method::POST, body)).await.unwrap_err();
+            assert!(matches!(ApiError::from_error(err), ApiError::Validation(_)));
+        }
+        assert_eq!(Member::from_row(&store.get("members", "m1").await.unwrap().unwrap()).unwrap().subscriptions.len(), 2);
+        assert!(one_click(&config, &store, request(Method::POST, "List-Unsubscribe=One-Click")).await.unwrap().unsubscribed);
+
+        let mailto = mailto_url(&config, &input);
+        let (address, subject) = mailto.split_once("?subject=").unwrap();
+        assert_eq!(address, "mailto:unsubscribe@example.com");
+        let subject: String = form_urlencoded::parse(format!("subject={}", subject).as_bytes()).next().unwrap().1.into_owned();
+        let parsed = from_subject(&subject).unwrap();
+        assert_eq!((parsed.member_id, parsed.topic_id, parsed.token), (input.member_id, input.topic_id, input.token));
+        assert!(from_subject("Re: hello").is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitStr, Path, Type};
+
+/**
+ * Derives `app_server_core::serialize::ServerSerialize`, mapping each field to an attribute of the row.
+ *
+ * Field types must implement `app_server_core::serialize::Attribute`. Fields that are an `Option`, `Vec` or map
+ * may be missing from a row, every other field is required unless marked `#[server(default)]`.
+ *
+ * Attributes:
+ *  - `#[server(id)]` on the `Option<String>` field holding the row's id (required)
+ *  - `#[server(version)]` on the `Option<u64>` field holding the row's version (required). It is stored as "version",
+ *    and can't be renamed.
+ *  - `#[server(rename = "name")]` to use a different attribute name in the row
+ *  - `#[server(default)]` to use `Default::default()` when the attribute is missing
+ *  - `#[server(lookup = "name")]` on a `String` field to also store it lowercased in the named attribute, so rows can be
+ *    found by it (whatever its case) through an index of the same name. Each value can only be used by one row.
+ *  - `#[server(remote = "Type")]` on the struct to implement the trait for a type defined in another crate.
+ *    The struct's fields must match the remote type's fields.
+ */
+#[proc_macro_derive(ServerSerialize, attributes(server))]
+pub fn derive_server_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    name: String,
+    id: bool,
+    version: bool,
+    default: bool,
+    lookup: Option<String>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    // Read the struct attributes
+    let mut remote: Option<Path> = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("server")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("remote") {
+                let path: LitStr = meta.value()?.parse()?;
+                remote = Some(path.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown server attribute"))
+            }
+        })?;
+    }
+
+    // Read the fields
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(Error::new_spanned(&input.ident, "ServerSerialize requires named fields")),
+        },
+        _ => return Err(Error::new_spanned(&input.ident, "ServerSerialize can only be derived for structs")),
+    };
+    let mut fields = vec![];
+    for field in named {
+        let ident = field.ident.clone().unwrap();
+        let mut parsed = Field {
+            name: ident.to_string(),
+            ident,
+            ty: field.ty.clone(),
+            id: false,
+            version: false,
+            default: false,
+            lookup: None,
+        };
+        let mut renamed = false;
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("server")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    parsed.id = true;
+                } else if meta.path.is_ident("version") {
+                    parsed.version = true;
+                } else if meta.path.is_ident("default") {
+                    parsed.default = true;
+                } else if meta.path.is_ident("rename") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    parsed.name = name.value();
+                    renamed = true;
+                } else if meta.path.is_ident("lookup") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    parsed.lookup = Some(name.value());
+                } else {
+                    return Err(meta.error("unknown server attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        // The store reads and bumps the version by its attribute name, so it has to stay "version"
+        if parsed.version && renamed {
+            return Err(Error::new_spanned(&parsed.ident, "the #[server(version)] field can't be renamed"));
+        }
+        if parsed.version {
+            parsed.name = "version".to_string();
+        }
+        fields.push(parsed);
+    }
+
+    let mut ids = fields.iter().filter(|field| field.id);
+    let id = match (ids.next(), ids.next()) {
+        (Some(id), None) => id,
+        (_, Some(second)) => return Err(Error::new_spanned(&second.ident, "only one field can be #[server(id)]")),
+        (None, None) => return Err(Error::new_spanned(&input.ident, "one field must be marked #[server(id)]")),
+    };
+    let id_ident = &id.ident;
+
+    let mut versions = fields.iter().filter(|field| field.version);
+    let version = match (versions.next(), versions.next()) {
+        (Some(version), None) => version,
+        (_, Some(second)) => return Err(Error::new_spanned(&second.ident, "only one field can be #[server(version)]")),
+        (None, None) => return Err(Error::new_spanned(&input.ident, "one field must be marked #[server(version)]")),
+    };
+    let version_ident = &version.ident;
+
+    let required = fields.iter()
+        .filter(|field| field.id || !(field.default || optional_type(&field.ty)))
+        .map(|field| &field.name);
+
+    let reads = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let name = &field.name;
+        let ty = &field.ty;
+        if field.id {
+            quote! { #ident: ::std::option::Option::Some(::app_server_core::serialize::read_attribute::<::std::string::String>(data, #name)?) }
+        } else if field.default || optional_type(ty) {
+            quote! { #ident: ::app_server_core::serialize::read_attribute_or_default::<#ty>(data, #name)? }
+        } else {
+            quote! { #ident: ::app_server_core::serialize::read_attribute::<#ty>(data, #name)? }
+        }
+    });
+
+    let writes = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let name = &field.name;
+        let lookup = field.lookup.as_ref().map(|lookup| quote! {
+            ::app_server_core::serialize::write_lookup(&mut map, #lookup, &self.#ident);
+        });
+        quote! {
+            ::app_server_core::serialize::write_attribute(&mut map, #name, &self.#ident);
+            #lookup
+        }
+    });
+
+    let lookups = fields.iter().filter_map(|field| {
+        let name = &field.name;
+        field.lookup.as_ref().map(|lookup| quote! { (#name, #lookup) })
+    });
+
+    let target = match &remote {
+        Some(path) => quote! { #path },
+        None => {
+            let ident = &input.ident;
+            quote! { #ident }
+        },
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let ty_generics = match &remote {
+        Some(_) => None,
+        None => Some(ty_generics),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::app_server_core::serialize::ServerSerialize for #target #ty_generics #where_clause {
+            const REQUIRED_FIELDS: &'static [&'static str] = &[#(#required),*];
+
+            const LOOKUPS: &'static [(&'static str, &'static str)] = &[#(#lookups),*];
+
+            fn from_row(data: &::app_server_core::store::Row) -> ::std::result::Result<Self, ::app_server_core::ApiError> {
+                ::std::result::Result::Ok(Self {
+                    #(#reads,)*
+                })
+            }
+
+            fn into_row(&self) -> ::app_server_core::store::Row {
+                let mut map = ::app_server_core::store::Row::new();
+                #(#writes)*
+                map
+            }
+
+            fn id(&self) -> ::std::option::Option<&str> {
+                self.#id_ident.as_deref()
+            }
+
+            fn set_id(&mut self, id: ::std::string::String) -> &mut Self {
+                self.#id_ident = ::std::option::Option::Some(id);
+                self
+            }
+
+            fn version(&self) -> ::std::option::Option<u64> {
+                self.#version_ident
+            }
+
+            fn set_version(&mut self, version: u64) -> &mut Self {
+                self.#version_ident = ::std::option::Option::Some(version);
+                self
+            }
+        }
+    })
+}
+
+/**
+ * Checks if the type can be left out of a row (i.e. an Option, list or map)
+ */
+fn optional_type(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path.path.segments.last()
+            .map(|segment| ["Option", "Vec", "HashMap"].contains(&&segment.ident.to_string()[..]))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::expand;
+
+    #[test]
+    fn test_version_cannot_be_renamed() {
+        let renamed = parse_quote! {
+            struct Item {
+                #[server(id)]
+                id: Option<String>,
+                #[server(version, rename = "v")]
+                version: Option<u64>,
+            }
+        };
+        let err = expand(renamed).unwrap_err();
+        assert_eq!(err.to_string(), "the #[server(version)] field can't be renamed");
+
+        let plain = parse_quote! {
+            struct Item {
+                #[server(id)]
+                id: Option<String>,
+                #[server(version)]
+                version: Option<u64>,
+            }
+        };
+        assert!(expand(plain).is_ok());
+    }
+}
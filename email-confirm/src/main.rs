@@ -1,4 +1,4 @@
-use app_server_core::{Member, Topic, serialize::ServerSerialize, EmailRequest, ConfirmEmailRequest, runtime::{StringResponse, run_handler}, ConfirmEmailResponse};
+use app_server_core::{ApiError, config::AppConfig, auth::{Authenticator, Permission, Principal}, cors::Cors, Member, Topic, EmailRequest, ConfirmEmailRequest, runtime::{StringResponse, run_handler}, ConfirmEmailResponse, crud::list_all_items, links::{LinkAction, LinkSigner}, broadcast::{Confirmation, confirm, send}, store::{Store, DynamoStore}};
 use lambda_http::{run, Request};
 use lambda_runtime::{service_fn, Error};
 use tokio::try_join;
@@ -11,44 +11,56 @@ async fn main() -> Result<(), Error> {
         .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
         .init();
 
-    run(service_fn(function_handler_wrap)).await
+    let config = AppConfig::load()?;
+    log::info!("Connecting clients...");
+    let aws_config = aws_config::load_from_env().await;
+    let sqs_client = aws_sdk_sqs::Client::new(&aws_config);
+    let store = DynamoStore::new(aws_sdk_dynamodb::Client::new(&aws_config));
+    let auth = Authenticator::from_config(&config, &store)?;
+    run(service_fn(|event| function_handler_wrap(&config, &auth, &store, &sqs_client, event))).await
 }
 
-async fn function_handler_wrap(event: Request) -> Result<StringResponse, Error> {
-    run_handler(&function_handler, event).await
+async fn function_handler_wrap(config: &AppConfig, auth: &Authenticator<'_>, store: &DynamoStore, sqs_client: &aws_sdk_sqs::Client, event: Request) -> Result<StringResponse, Error> {
+    run_handler(&Cors::from_config(config), auth, Permission::ApproveEmails, &|principal, input| function_handler(config, store, sqs_client, principal, input), event).await
 }
 
-async fn function_handler(input: ConfirmEmailRequest) -> Result<ConfirmEmailResponse, Error> {
-    log::info!("Connecting clients...");
-    let config = aws_config::load_from_env().await;
-    let sqs_client = aws_sdk_sqs::Client::new(&config);
-    let dyno_client = aws_sdk_dynamodb::Client::new(&config);
-
+async fn function_handler(config: &AppConfig, store: &DynamoStore, sqs_client: &aws_sdk_sqs::Client, principal: Principal, input: ConfirmEmailRequest) -> Result<ConfirmEmailResponse, Error> {
+    // Only the confirm links emailed to the topic's moderators have a valid token
+    LinkSigner::from_config(config).verify(&input.token, LinkAction::ConfirmEmail, &input.topic_id, &input.email_id)?;
     log::info!("Fetching endpoints & members...");
-    let (topics, members) = try_join!(get_topics(&dyno_client), get_members(&dyno_client))?;
+    let (topics, members) = try_join!(get_topics(store, config), get_members(store, config))?;
 
     if let Some(topic) = topics.into_iter().find(|topic| topic.id.as_ref() == Some(&input.topic_id)) {
-        queue_emails(&topic, &members, &input.email_id, &sqs_client).await?;
+        if !principal.moderates(&topic) {
+            return Err(ApiError::Forbidden(format!("{} doesn't moderate {}", principal.actor(), topic.name)).into());
+        }
+        // Only the first confirm sends the email, so following the link twice doesn't send it twice
+        let broadcast = match confirm(store, &config.broadcasts_table, &input.topic_id, &input.email_id, principal.actor()).await? {
+            Confirmation::Claimed(broadcast) => broadcast,
+            Confirmation::AlreadyConfirmed(broadcast) => return Ok(ConfirmEmailResponse {
+                topic: Some(topic),
+                broadcast: Some(broadcast),
+                queued: false,
+            }),
+        };
+        // If queueing fails the error is returned and the broadcast is left failed, for confirming again to finish
+        let subscribers: Vec<Member> = members.into_iter().filter(|member| member.subscriptions.iter().any(|sub| topic.has_subscription(sub))).collect();
+        let broadcast = send(store, &config.broadcasts_table, broadcast, &subscribers, |member| queue_email(&topic, member, sqs_client, &input.email_id, config)).await?;
         Ok(ConfirmEmailResponse { 
-            topic: Some(topic)
+            topic: Some(topic),
+            broadcast: Some(broadcast),
+            queued: true,
         })
     } else {
         Ok(ConfirmEmailResponse {
             topic: None,
+            broadcast: None,
+            queued: false,
         })
     }
 }
 
-async fn queue_emails(topic: &Topic, members: &Vec<Member>, email_id: &str, client: &aws_sdk_sqs::Client) -> Result<(), Error> {
-    for member in members {
-        if member.subscriptions.iter().any(|sub| topic.endpoint.contains(sub)) {
-            queue_email(topic, member, client, email_id).await?;
-        }
-    }
-    Ok(())
-}
-
-async fn queue_email(topic: &Topic, member: &Member, client: &aws_sdk_sqs::Client, email_id: &str) -> Result<(), Error> {
+async fn queue_email(topic: &Topic, member: &Member, client: &aws_sdk_sqs::Client, email_id: &str, config: &AppConfig) -> Result<(), Error> {
     let event = EmailRequest {
         topic: topic.clone(),
         member: member.clone(),
@@ -57,68 +69,21 @@ async fn queue_email(topic: &Topic, member: &Member, client: &aws_sdk_sqs::Clien
     };
 
     let result = client.send_message()
-        .queue_url("https://sqs.us-east-1.amazonaws.com/400928329577/sinln-output-queue") // TODO don't hard code output queue URL
+        .queue_url(&config.output_queue_url)
         .message_body(serde_json::to_string(&event)?)
         .send()
-        .await?;
+        .await
+        .map_err(ApiError::from_sdk)?;
 
     log::info!("Email queue: {:?}", result);
 
     Ok(())
 }
 
-async fn get_topics(client: &aws_sdk_dynamodb::Client) -> Result<Vec<Topic>, Error> {
-    let table_response = client.scan()
-        .table_name("sinln-topics")
-        .send().await;
-    log::info!("Topics data: {:?}", table_response);
-    let table_response = table_response?;
-
-    // Convert json into topics
-    let result = table_response.items()
-        .map(|items| {
-            let topics: Vec<Topic> = items.into_iter().filter_map(|row| {
-                match Topic::from_row(row) {
-                    Ok(m) => Some(m),
-                    Err(_err) => None,
-                }
-            }).collect();
-            topics
-        });
-    
-    // TODO Handle the case of None
-    let topics = match result {
-        Some(x) => x,
-        None => return Err(Box::new(app_server_core::RuntimeError::from_str("Scan resulted in None? Why would that happen?"))),
-    };
-
-    Ok(topics)
+async fn get_topics(store: &dyn Store, config: &AppConfig) -> Result<Vec<Topic>, Error> {
+    list_all_items(store, &config.topics_table).await
 }
 
-async fn get_members(client: &aws_sdk_dynamodb::Client) -> Result<Vec<Member>, Error> {
-    let table_response = client.scan()
-        .table_name("sinln-members")
-        .send().await;
-    log::info!("Members data: {:?}", table_response);
-    let table_response = table_response?;
-
-    // Convert json into members
-    let result = table_response.items()
-        .map(|items| {
-            let members: Vec<Member> = items.into_iter().filter_map(|row| {
-                match Member::from_row(row) {
-                    Ok(m) => Some(m),
-                    Err(_err) => None,
-                }
-            }).collect();
-            members
-        });
-    
-    // TODO Handle the case of None
-    let members = match result {
-        Some(x) => x,
-        None => return Err(Box::new(app_server_core::RuntimeError::from_str("Scan resulted in None? Why would that happen?"))),
-    };
-
-    Ok(members)
+async fn get_members(store: &dyn Store, config: &AppConfig) -> Result<Vec<Member>, Error> {
+    list_all_items(store, &config.members_table).await
 }
\ No newline at end of file
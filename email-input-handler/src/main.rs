@@ -1,4 +1,4 @@
-use app_server_core::{Member, Topic, serialize::ServerSerialize, EmailRequest};
+use app_server_core::{config::AppConfig, BroadcastStatus, Member, Topic, EmailRequest, crud::list_all_items, serialize::ServerSerialize, store::{Store, DynamoStore, DELETED_AT}, unsubscribe::{from_subject, unsubscribe, unsubscribe_address}, broadcast::add_pending};
 use aws_lambda_events::{sns::SnsMessage, sqs::SqsEvent,ses::SimpleEmailService};
 use lambda_runtime::{service_fn, LambdaEvent, Error};
 use serde_json::Value;
@@ -11,10 +11,11 @@ async fn main() -> Result<(), Error> {
         .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
         .init();
 
-    lambda_runtime::run(service_fn(handler)).await
+    let config = AppConfig::load()?;
+    lambda_runtime::run(service_fn(|event| handler(&config, event))).await
 }
 
-async fn handler(event: LambdaEvent<Value>) -> Result<(), Error> {
+async fn handler(config: &AppConfig, event: LambdaEvent<Value>) -> Result<(), Error> {
     log::info!("Loading config...");
 
     let (event_value, _context) = event.into_parts();   
@@ -22,32 +23,36 @@ async fn handler(event: LambdaEvent<Value>) -> Result<(), Error> {
     let sqs_event: SqsEvent = serde_json::from_value(event_value)?;
     
     log::info!("Connecting clients...");
-    let config = aws_config::load_from_env().await;
-    let sqs_client = aws_sdk_sqs::Client::new(&config);
-    let dyno_client = aws_sdk_dynamodb::Client::new(&config);
+    let aws_config = aws_config::load_from_env().await;
+    let sqs_client = aws_sdk_sqs::Client::new(&aws_config);
+    let store = DynamoStore::new(aws_sdk_dynamodb::Client::new(&aws_config));
 
     log::info!("Fetching endpoint & members...");
-    let topics = get_topics(&dyno_client).await?;
+    let topics = get_topics(&store, config).await?;
 
     for sqs_record in &sqs_event.records {
         log::info!("Decoding SNS Record");
-        let sns_message: SnsMessage = serde_json::from_str(&sqs_record.body.as_ref().unwrap())?;
+        let sns_message: SnsMessage = serde_json::from_str(sqs_record.body.as_ref().unwrap())?;
         log::info!("Decoding SES Record");
         let ses_service: SimpleEmailService = serde_json::from_str(&sns_message.message)?;
         log::info!("Get message id");
         let message_id = ses_service.mail.message_id.unwrap();
 
         for target in &ses_service.mail.destination { 
-            if let Some(topic) = topics.iter().find(|topic| &topic.endpoint == target) {
-                let member = Member {
-                    id: Some("---".to_owned()),
-                    name: "Sender".to_owned(),
-                    email: ses_service.mail.source.as_ref().unwrap().to_owned(),
-                    address: None,
-                    mobile: None,
-                    subscriptions: vec![],
-                };
-                queue_email(&topic, &member, &message_id, &sqs_client).await?;
+            if target.eq_ignore_ascii_case(&unsubscribe_address(config)) {
+                unsubscribe_by_email(ses_service.mail.common_headers.subject.as_deref(), &store, config).await;
+            } else if let Some(topic) = topics.iter().find(|topic| &topic.endpoint == target) {
+                let broadcast = add_pending(&store, &config.broadcasts_table, topic.id.as_deref().unwrap_or_default(), &message_id).await?;
+                // A retried email that has already been confirmed doesn't need confirming again
+                if broadcast.status == BroadcastStatus::Pending {
+                    let moderators = get_moderators(&store, config, topic).await?;
+                    if moderators.is_empty() {
+                        log::warn!("Topic {} has no moderators to confirm email {}", topic.name, message_id);
+                    }
+                    for moderator in &moderators {
+                        queue_email(topic, moderator, &message_id, &sqs_client, config).await?;
+                    }
+                }
             } else {
                 todo!("Send bad endpoint email back");
             }
@@ -57,7 +62,23 @@ async fn handler(event: LambdaEvent<Value>) -> Result<(), Error> {
     Ok(())
 }
 
-async fn queue_email(topic: &Topic, member: &Member, email_id: &str, client: &aws_sdk_sqs::Client) -> Result<(), Error> {
+/**
+ * Unsubscribes for mail apps that email the `List-Unsubscribe` mailto link instead of posting.
+ * Failures are only logged, since there's no one to tell.
+ */
+async fn unsubscribe_by_email(subject: Option<&str>, store: &dyn Store, config: &AppConfig) {
+    let subject = subject.unwrap_or_default();
+    let Some(request) = from_subject(subject) else {
+        log::warn!("Unsubscribe email has no request in its subject: {:?}", subject);
+        return;
+    };
+    match unsubscribe(config, store, request).await {
+        Ok(response) => log::info!("Unsubscribe email: {:?}", response),
+        Err(err) => log::warn!("Unsubscribe email failed: {}", err),
+    }
+}
+
+async fn queue_email(topic: &Topic, member: &Member, email_id: &str, client: &aws_sdk_sqs::Client, config: &AppConfig) -> Result<(), Error> {
     let event = EmailRequest {
         topic: topic.clone(),
         member: member.clone(),
@@ -66,7 +87,7 @@ async fn queue_email(topic: &Topic, member: &Member, email_id: &str, client: &aw
     };
 
     let result = client.send_message()
-        .queue_url("https://sqs.us-east-1.amazonaws.com/400928329577/sinln-output-queue") // TODO don't hard code output queue URL
+        .queue_url(&config.output_queue_url)
         .message_body(serde_json::to_string(&event)?)
         .send()
         .await?;
@@ -76,30 +97,21 @@ async fn queue_email(topic: &Topic, member: &Member, email_id: &str, client: &aw
     Ok(())
 }
 
-async fn get_topics(client: &aws_sdk_dynamodb::Client) -> Result<Vec<Topic>, Error> {
-    let table_response = client.scan()
-        .table_name("sinln-topics")
-        .send().await;
-    log::info!("Topics data: {:?}", table_response);
-    let table_response = table_response?;
-
-    // Convert json into topics
-    let result = table_response.items()
-        .map(|items| {
-            let topics: Vec<Topic> = items.into_iter().filter_map(|row| {
-                match Topic::from_row(row) {
-                    Ok(m) => Some(m),
-                    Err(_err) => None,
-                }
-            }).collect();
-            topics
-        });
-    
-    // TODO Handle the case of None
-    let topics = match result {
-        Some(x) => x,
-        None => return Err(Box::new(app_server_core::RuntimeError::from_str("Scan resulted in None? Why would that happen?"))),
-    };
+/**
+ * The members who can confirm emails sent to the topic, leaving out any in the trash
+ */
+async fn get_moderators(store: &dyn Store, config: &AppConfig, topic: &Topic) -> Result<Vec<Member>, Error> {
+    let rows = store.get_batch(&config.members_table, &topic.moderators).await?;
+    let mut moderators = vec![];
+    for row in rows.values().filter(|row| !row.contains_key(DELETED_AT)) {
+        match Member::from_row(row) {
+            Ok(member) => moderators.push(member),
+            Err(err) => log::warn!("Moderator of {} can't be read: {}", topic.name, err),
+        }
+    }
+    Ok(moderators)
+}
 
-    Ok(topics)
+async fn get_topics(store: &dyn Store, config: &AppConfig) -> Result<Vec<Topic>, Error> {
+    list_all_items(store, &config.topics_table).await
 }
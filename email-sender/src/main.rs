@@ -1,4 +1,4 @@
-use app_server_core::{EmailRequest};
+use app_server_core::{EmailRequest, Topic, UnsubscribeRequest, config::AppConfig, links::{LinkAction, LinkSigner, CONFIRM_LINK_LIFETIME, UNSUBSCRIBE_LINK_LIFETIME}, unsubscribe::{one_click_url, mailto_url}};
 use aws_sdk_dynamodb::primitives::Blob;
 use aws_sdk_sesv2::types::{Destination, EmailContent, RawMessage};
 use email_format::{Email, rfc5322::Parsable};
@@ -15,45 +15,55 @@ async fn main() -> Result<(), Error> {
         .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
         .init();
 
-    lambda_runtime::run(service_fn(event_handler)).await
+    let config = AppConfig::load()?;
+    lambda_runtime::run(service_fn(|event| event_handler(&config, event))).await
 }
 
-async fn event_handler(event: LambdaEvent<Value>) -> Result<(), Error> {
+async fn event_handler(config: &AppConfig, event: LambdaEvent<Value>) -> Result<(), Error> {
     log::info!("Loading SQS Event");
     let (event_value, _context) = event.into_parts();
     let sqs_event: SqsEvent = serde_json::from_value(event_value)?;
     
     log::info!("Loading Config/clients");
-    let config = aws_config::load_from_env().await;
-    let s3_client = aws_sdk_s3::Client::new(&config);
-    let ses_client = aws_sdk_sesv2::Client::new(&config);
+    let aws_config = aws_config::load_from_env().await;
+    let s3_client = aws_sdk_s3::Client::new(&aws_config);
+    let ses_client = aws_sdk_sesv2::Client::new(&aws_config);
     
     for sqs_record in &sqs_event.records {
         log::info!("Decoding SQS Record");
         log::info!("SQS Body: {}", &sqs_record.body.as_ref().unwrap());
-        let request: EmailRequest = serde_json::from_str(&sqs_record.body.as_ref().unwrap())?;
+        let request: EmailRequest = serde_json::from_str(sqs_record.body.as_ref().unwrap())?;
         log::info!("Getting email content");
-        let email_content = get_email(&request.email_id[..], &s3_client).await?;
+        let email_content = get_email(&request.email_id[..], &s3_client, config).await?;
 
-        send_email(&request, &ses_client, &email_content).await?;
+        send_email(&request, &ses_client, &email_content, config).await?;
     }
     
     Ok(()) 
 }
 
-async fn send_email(request: &EmailRequest, client: &aws_sdk_sesv2::Client, email_template: &str) -> Result<(), Error> {
+async fn send_email(request: &EmailRequest, client: &aws_sdk_sesv2::Client, email_template: &str, config: &AppConfig) -> Result<(), Error> {
     log::info!("Interperetting Email....");
     
     let (mut email_obj, _remainder) = Email::parse(email_template.as_bytes())?;
 
+    let signer = LinkSigner::from_config(config);
+    let topic_id = request.topic.id.as_ref().unwrap();
+    let footer = if request.confirm_link {
+        let token = signer.sign(LinkAction::ConfirmEmail, topic_id, &request.email_id, CONFIRM_LINK_LIFETIME)?;
+        format!("\r\n\r\nConfirm email: {}/email-confirm?topic={}&email={}&token={}", config.link_base, topic_id, &request.email_id, token)
+    } else {
+        let member_id = request.member.id.as_ref().unwrap();
+        let unsubscribe = UnsubscribeRequest {
+            member_id: member_id.clone(),
+            topic_id: topic_id.clone(),
+            token: signer.sign(LinkAction::Unsubscribe, topic_id, member_id, UNSUBSCRIBE_LINK_LIFETIME)?,
+        };
+        set_list_headers(&mut email_obj, list_headers(&request.topic, &unsubscribe, config))?;
+        format!("\r\n\r\nUnsubscribe: {}/unsubscribe?member={}&topic={}&token={}", config.link_base, member_id, topic_id, unsubscribe.token)
+    };
     if let Some(body) = email_obj.get_body() {
-        let mut message = body.to_string();
-        
-        if request.confirm_link {
-            message += &format!("\r\n\r\nConfirm email: https://sinln.mdsimmo.com/email-confirm?topic={}&email={}", request.topic.id.as_ref().unwrap(), &request.email_id);
-        } else {
-            message += &format!("\r\n\r\nUnsubscibe: https://sinln.mdsimmo.com/unsubscribe?member={}&topic={}", request.topic.id.as_ref().unwrap(), request.member.id.as_ref().unwrap()) 
-        }
+        let message = body.to_string() + &footer;
         email_obj.set_body(message.as_str()).unwrap();
     }
     let email = email_obj.to_string();
@@ -85,9 +95,41 @@ async fn send_email(request: &EmailRequest, client: &aws_sdk_sesv2::Client, emai
     Ok(())
 }
 
-async fn get_email(message_id: &str, client: &aws_sdk_s3::Client) -> Result<String, Error> {
+/**
+ * The RFC 2369 headers that tell mail apps which list the email is from, and how to post to it and leave it.
+ * Mail apps that support RFC 8058 unsubscribe with one click by posting to the first unsubscribe link.
+ */
+fn list_headers(topic: &Topic, unsubscribe: &UnsubscribeRequest, config: &AppConfig) -> Vec<(&'static str, String)> {
+    let name: String = topic.name.chars().filter(|c| *c != '"' && *c != '\\').collect();
+    vec![
+        ("List-Id", format!("\"{}\" <{}>", name, topic.endpoint.replace('@', "."))),
+        ("List-Post", format!("<mailto:{}>", topic.endpoint)),
+        ("List-Unsubscribe", format!("<{}>, <{}>", one_click_url(config, unsubscribe), mailto_url(config, unsubscribe))),
+        ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".to_string()),
+    ]
+}
+
+/**
+ * Replaces any list headers the email arrived with, so mail apps don't offer to leave some other list
+ */
+fn set_list_headers(email: &mut Email, headers: Vec<(&str, String)>) -> Result<(), Error> {
+    let kept: Vec<_> = email.get_optional_fields().into_iter()
+        .filter(|field| !field.name.to_string().to_lowercase().starts_with("list-"))
+        .collect();
+    email.clear_optional_fields();
+    for field in kept {
+        email.add_optional_field(field)?;
+    }
+    for (name, value) in headers {
+        // Field values are written just as given, so need the space after the colon
+        email.add_optional_field((name, format!(" {}", value).as_str()))?;
+    }
+    Ok(())
+}
+
+async fn get_email(message_id: &str, client: &aws_sdk_s3::Client, config: &AppConfig) -> Result<String, Error> {
     let get_result = client.get_object()
-        .bucket("sinln-input-emails")
+        .bucket(&config.input_email_bucket)
         .key(message_id)
         .send().await?;
 
@@ -99,8 +141,11 @@ async fn get_email(message_id: &str, client: &aws_sdk_s3::Client) -> Result<Stri
 
 #[cfg(test)]
 mod tests {
+    use app_server_core::{Topic, UnsubscribeRequest, config::AppConfig};
     use email_format::{Email, rfc5322::Parsable};
 
+    use super::{list_headers, set_list_headers};
+
     #[test]
     fn test_sender() {
         let input = "".as_bytes();//include_bytes!("p3dl6t0bta05uvn533d6ev3dj9ts1r4g6c399ag1.txt");
@@ -117,4 +162,23 @@ mod tests {
         println!("Output: {}", email);
         println!("Output: {:?}", String::from_utf8(_remainder.into_iter().map(|&a|a).collect()).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_list_headers() {
+        let mut config = AppConfig::for_tests();
+        config.api_base = "https://api.sinln.mdsimmo.com".to_string();
+        config.sending_domain = "mdsimmo.com".to_string();
+        let topic = Topic { id: Some("t1".to_string()), name: "Club \"News\"".to_string(), endpoint: "news@mdsimmo.com".to_string(), default: false, moderators: vec![], version: None };
+        let unsubscribe = UnsubscribeRequest { member_id: "m1".to_string(), topic_id: "t1".to_string(), token: "abc.def".to_string() };
+
+        let input = "From: alice@example.com\r\nDate: Sun, 1 Jan 2023 00:00:00 +0000\r\nList-Id: <other.example.com>\r\nSubject: Hi\r\n\r\nHello\r\n".as_bytes();
+        let (mut email, _) = Email::parse(input).unwrap();
+        set_list_headers(&mut email, list_headers(&topic, &unsubscribe, &config)).unwrap();
+        let email = email.to_string();
+        assert!(!email.contains("other.example.com"));
+        assert!(email.contains("List-Id: \"Club News\" <news.mdsimmo.com>\r\n"));
+        assert!(email.contains("List-Post: <mailto:news@mdsimmo.com>\r\n"));
+        assert!(email.contains("List-Unsubscribe: <https://api.sinln.mdsimmo.com/members-unsubscribe-one-click?member=m1&topic=t1&token=abc.def>, <mailto:unsubscribe@mdsimmo.com?subject=unsubscribe%20m1%20t1%20abc.def>\r\n"));
+        assert!(email.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"));
+    }
+}
@@ -1,5 +1,5 @@
-use app_server_core::{Member, crud::list_items, ListResponse, ListRequest, runtime::{StringResponse, run_handler}};
-use lambda_http::{run, service_fn, Error, Request};
+use app_server_core::{config::AppConfig, auth::Authenticator, handlers::api, store::DynamoStore};
+use lambda_http::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -9,13 +9,10 @@ async fn main() -> Result<(), Error> {
         .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
         .init();
 
-    run(service_fn(function_handler_wrap)).await
+    let config = AppConfig::load()?;
+    // Connect once, so every invocation of a warm lambda shares the client
+    let store = DynamoStore::from_env().await;
+    let auth = Authenticator::from_config(&config, &store)?;
+    let router = api(&config, &store, &auth).only("/members-list");
+    router.run_lambda().await
 }
-
-async fn function_handler_wrap(event: Request) -> Result<StringResponse, Error> {
-    run_handler(&function_handler, event).await
-}
-
-pub async fn function_handler(_event: ListRequest) -> Result<ListResponse<Member>, Error> {
-    list_items(_event, "sinln-members").await
-}
\ No newline at end of file
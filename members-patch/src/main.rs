@@ -0,0 +1,18 @@
+use app_server_core::{config::AppConfig, auth::Authenticator, handlers::api, store::DynamoStore};
+use lambda_http::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .without_time()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+        .init();
+
+    let config = AppConfig::load()?;
+    // Connect once, so every invocation of a warm lambda shares the client
+    let store = DynamoStore::from_env().await;
+    let auth = Authenticator::from_config(&config, &store)?;
+    let router = api(&config, &store, &auth).only("/members-patch");
+    router.run_lambda().await
+}
@@ -0,0 +1,60 @@
+use app_server_core::{Member, crud::backfill_lookups, store::DynamoStore};
+use lambda_http::Error;
+
+const USAGE: &str = "\
+Fills in the lookup attributes (like `email_key`) of member rows written before the lookup existed.
+Safe to run more than once, and while the API is serving.
+
+Usage: sinln-backfill [--table <name>] [--dynamodb <url>]
+
+  --table     The members table (default sinln-members)
+  --dynamodb  Use DynamoDB at the URL (e.g. DynamoDB Local at http://localhost:8000)
+              instead of the AWS account and region from the environment";
+
+/**
+ * Options given on the command line
+ */
+struct Options {
+    table: String,
+    dynamodb: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+        .init();
+
+    let options = match read_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(problem) => {
+            eprintln!("{}\n\n{}", problem, USAGE);
+            std::process::exit(2);
+        },
+    };
+
+    let store = match &options.dynamodb {
+        Some(url) => DynamoStore::from_endpoint(url).await,
+        None => DynamoStore::from_env().await,
+    };
+    let filled = backfill_lookups::<Member>(&store, &options.table).await?;
+    println!("Filled in {} rows of {}", filled, options.table);
+    Ok(())
+}
+
+fn read_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options { table: "sinln-members".to_string(), dynamodb: None };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--table" => options.table = value()?,
+            "--dynamodb" => options.dynamodb = Some(value()?),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
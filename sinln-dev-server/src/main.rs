@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use app_server_core::{Member, Role, UpdateRequest, audit::Audit, auth::{Authenticator, EMAIL_LOOKUP}, config::AppConfig, crud::{IdStrategy, RowAccess, update_items}, handlers::api, router::Router, store::{Store, MemoryStore, DynamoStore}};
+use hyper::{Server, service::{make_service_fn, service_fn}};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode, get_current_timestamp};
+use lambda_http::{Body, Error};
+use serde_json::json;
+
+/** The secret the dev server signs its tokens with. Only ever trusted by the dev server. */
+const DEV_SECRET: &[u8] = b"sinln-dev-server-secret-not-for-production";
+
+/** base64url of `DEV_SECRET` */
+const DEV_KEY_SET: &str = r#"{"keys": [{"kty": "oct", "k": "c2lubG4tZGV2LXNlcnZlci1zZWNyZXQtbm90LWZvci1wcm9kdWN0aW9u"}]}"#;
+
+/** How long the printed admin token works for */
+const TOKEN_LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+
+const USAGE: &str = "\
+Runs the API on this machine.
+
+Usage: sinln-dev-server [--port <port>] [--dynamodb <url>] [--admin <email>]
+
+  --port      Port to listen on (default 9000)
+  --dynamodb  Store data in DynamoDB at the URL (e.g. DynamoDB Local at http://localhost:8000)
+              instead of in memory. The tables must already exist.
+  --admin     Email of the admin member to sign in as (default admin@localhost)
+
+Settings are read from SINLN_* variables and SINLN_CONFIG_FILE like the lambdas,
+with defaults that need no AWS account.";
+
+/**
+ * Options given on the command line
+ */
+struct Options {
+    port: u16,
+    dynamodb: Option<String>,
+    admin: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+        .init();
+
+    let options = match read_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(problem) => {
+            eprintln!("{}\n\n{}", problem, USAGE);
+            std::process::exit(2);
+        },
+    };
+
+    // Everything lives until the server stops, which is when the process exits
+    let config: &'static AppConfig = Box::leak(Box::new(AppConfig::load_with_defaults(defaults())?));
+    let store: &'static dyn Store = match &options.dynamodb {
+        Some(url) => Box::leak(Box::new(DynamoStore::from_endpoint(url).await)),
+        None => Box::leak(Box::new(MemoryStore::new())),
+    };
+    let auth: &'static Authenticator = Box::leak(Box::new(Authenticator::from_config(config, store)?));
+    let router: &'static Router = Box::leak(Box::new(api(config, store, auth)));
+
+    add_admin(config, store, &options.admin).await?;
+    if config.auth_keys == DEV_KEY_SET {
+        println!("Sign in as {} with:\n  Authorization: Bearer {}\n", options.admin, admin_token(config, &options.admin)?);
+    }
+
+    let address = SocketAddr::from(([127, 0, 0, 1], options.port));
+    println!("Serving {} paths at http://{}", router.paths().len(), address);
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| handle(router, request)))
+    });
+    Server::bind(&address).serve(make_service).await?;
+    Ok(())
+}
+
+fn read_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options { port: 9000, dynamodb: None, admin: "admin@localhost".to_string() };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--port" => options.port = value()?.parse().map_err(|_| "--port must be a number".to_string())?,
+            "--dynamodb" => options.dynamodb = Some(value()?),
+            "--admin" => options.admin = value()?,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/**
+ * Settings that work on a laptop. The queue and bucket aren't used by any route, so needn't exist.
+ */
+fn defaults() -> HashMap<String, String> {
+    [
+        ("members_table", "sinln-members"),
+        ("topics_table", "sinln-topics"),
+        ("audit_table", "sinln-audit"),
+        ("broadcasts_table", "sinln-broadcasts"),
+        ("output_queue_url", "https://sqs.localhost/sinln-email-output"),
+        ("input_email_bucket", "sinln-email-input"),
+        ("link_base", "http://localhost:3000"),
+        ("api_base", "http://localhost:9000"),
+        ("sending_domain", "localhost"),
+        ("auth_issuer", "sinln-dev-server"),
+        ("auth_audience", "sinln"),
+        ("auth_keys", DEV_KEY_SET),
+        ("link_keys", "sinln-dev-server-link-secret-not-for-production"),
+        ("cors_origins", "http://localhost:3000,http://localhost:8080"),
+    ].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+/**
+ * Makes sure there is an admin member to sign in as
+ */
+async fn add_admin(config: &AppConfig, store: &dyn Store, email: &str) -> Result<(), Error> {
+    if !store.find(&config.members_table, EMAIL_LOOKUP, &email.to_lowercase()).await?.is_empty() {
+        return Ok(());
+    }
+    let admin = Member {
+        id: None,
+        name: "Admin".to_string(),
+        email: email.to_string(),
+        address: None,
+        mobile: None,
+        subscriptions: vec![],
+        role: Role::Admin,
+        version: None,
+    };
+    let audit = Audit::new(store, &config.audit_table, "sinln-dev-server");
+    let request = UpdateRequest { values: vec![admin], all_or_nothing: true };
+    update_items(store, request, &config.members_table, IdStrategy::Timestamped, &config.validation(), &RowAccess::All, &audit).await?;
+    Ok(())
+}
+
+fn admin_token(config: &AppConfig, email: &str) -> Result<String, Error> {
+    let claims = json!({
+        "sub": email,
+        "email": email,
+        "email_verified": true,
+        "iss": config.auth_issuer,
+        "aud": config.auth_audience,
+        "exp": get_current_timestamp() + TOKEN_LIFETIME_SECS,
+    });
+    Ok(encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(DEV_SECRET))?)
+}
+
+/**
+ * Runs a request through the router, as API Gateway would
+ */
+async fn handle(router: &Router<'_>, request: hyper::Request<hyper::Body>) -> Result<hyper::Response<hyper::Body>, Error> {
+    let (parts, body) = request.into_parts();
+    let bytes = hyper::body::to_bytes(body).await?;
+    let body = match String::from_utf8(bytes.to_vec()) {
+        Ok(text) if text.is_empty() => Body::Empty,
+        Ok(text) => Body::Text(text),
+        Err(err) => Body::Binary(err.into_bytes()),
+    };
+    let path = parts.uri.path().to_string();
+    let response = router.handle(lambda_http::Request::from_parts(parts, body)).await?;
+    log::info!("{} {}", response.status().as_u16(), path);
+    Ok(response.map(hyper::Body::from))
+}